@@ -0,0 +1,39 @@
+#import bevy_sprite::mesh2d_view_bindings
+
+struct Vertex {
+    @builtin(vertex_index) index: u32,
+    // bottom left corner and z of the rect
+    @location(0) position: vec3<f32>,
+    @location(1) size: vec2<f32>,
+    @location(2) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    // two triangles over the unit square, without a vertex buffer of their own
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 1.0),
+    );
+
+    let corner = vertex.position.xy + corners[vertex.index] * vertex.size;
+
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * vec4<f32>(corner, vertex.position.z, 1.0);
+    out.color = vertex.color;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::{
+    path_to_poly::PathEnds,
+    types::{LayerId, TiledRendererSettings, MAX_BATCH_SIZE},
+};
+
+/// Command line options, parsed once at startup and inserted as a resource
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version, about = "Tiled renderer for VLSIR layout libraries")]
+pub struct CliArgs {
+    /// Path of the library to open, either a VLSIR protobuf or a GDSII stream
+    pub input: PathBuf,
+
+    /// Name of the cell to render, defaults to the last cell in the library
+    #[clap(short, long)]
+    pub top_cell: Option<String>,
+
+    /// Only render shapes on these layer numbers, e.g. `--layers 68,69,70`
+    #[clap(short, long, value_delimiter = ',')]
+    pub layers: Vec<i16>,
+
+    /// KLayout layer properties file (.lyp) to take layer colours and visibility from, layers
+    /// it doesn't cover get colours from the default palette
+    #[clap(long)]
+    pub lyp: Option<PathBuf>,
+
+    /// Drawing order of layers from the bottom up, e.g. `--layer-stack 66/20,67/20,68/20`.
+    /// Layers left out are drawn below those listed, by layer number and datatype
+    #[clap(long, value_delimiter = ',')]
+    pub layer_stack: Vec<LayerId>,
+
+    /// Write the per-tile shape count heatmap to this csv file
+    #[clap(long)]
+    pub heatmap_csv: Option<PathBuf>,
+
+    /// Save the accumulation texture to this png file once every tile has been rendered
+    #[clap(long)]
+    pub png: Option<PathBuf>,
+
+    /// Render every tile without opening a window, save the result to `--png` and exit
+    #[clap(long, requires = "png")]
+    pub headless: bool,
+
+    /// Number of tiles along each side of the tile grid [default: 64]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=4096))]
+    pub num_tiles: Option<u32>,
+
+    /// Side length of a tile in the accumulation texture, in pixels [default: 64]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=4096))]
+    pub tile_size_px: Option<u32>,
+
+    /// Alpha of shape fills [default: 0.1]
+    #[clap(long)]
+    pub fill_alpha: Option<f32>,
+
+    /// Width of shape outlines, in hi-res texture pixels [default: 10]
+    #[clap(long)]
+    pub outline_width: Option<f32>,
+
+    /// End style of paths: flush, square, round or custom:<begin>,<end> [default: flush]
+    #[clap(long)]
+    pub path_ends: Option<PathEnds>,
+
+    /// Font file to draw text labels with, labels aren't drawn without one
+    #[clap(long)]
+    pub label_font: Option<PathBuf>,
+
+    /// Height of label text, in database units [default: 1000]
+    #[clap(long)]
+    pub label_height: Option<u32>,
+
+    /// Labels smaller than this in the accumulation texture, in pixels, aren't drawn [default: 8]
+    #[clap(long)]
+    pub min_label_px: Option<f32>,
+
+    /// Distance between the lines or dots of fill patterns, in accumulation texture pixels
+    /// [default: 4]
+    #[clap(long)]
+    pub pattern_spacing_px: Option<f32>,
+
+    /// Highest zoom level of the tile pyramid, each level doubling the resolution of the one
+    /// below [default: 6]
+    #[clap(long)]
+    pub max_zoom_level: Option<u8>,
+
+    /// Number of zoomed in tiles kept before those out of view are evicted [default: 1024]
+    #[clap(long)]
+    pub pyramid_cache_tiles: Option<usize>,
+
+    /// Re-render what is on screen at the window's resolution whenever the view stops moving
+    #[clap(long)]
+    pub live_view: bool,
+
+    /// How long the view has to stay still before the live view is re-rendered, in
+    /// milliseconds [default: 250]
+    #[clap(long)]
+    pub live_view_settle_ms: Option<u32>,
+
+    /// Number of tiles rendered per frame, up to 8, each with a hi-res texture of its own, so
+    /// every extra tile takes as much video memory as the first. 1 renders and waits for one
+    /// tile at a time [default: 1]
+    #[clap(long)]
+    pub batch_size: Option<usize>,
+
+    /// Megabytes of tessellated shapes kept for reuse across tiles and re-renders
+    /// [default: 256]
+    #[clap(long)]
+    pub tessellation_cache_mb: Option<usize>,
+
+    /// Rasterize every tile on the CPU instead of the GPU, save the result to `--png` and exit
+    #[clap(long, requires = "png", conflicts_with = "headless")]
+    pub cpu: bool,
+}
+
+impl CliArgs {
+    /// The renderer settings, with anything not given on the command line left at its default
+    pub fn renderer_settings(&self) -> TiledRendererSettings {
+        let default = TiledRendererSettings::default();
+
+        TiledRendererSettings {
+            num_tiles: self.num_tiles.unwrap_or(default.num_tiles),
+            tile_size_in_px: self.tile_size_px.unwrap_or(default.tile_size_in_px),
+            alpha: self.fill_alpha.unwrap_or(default.alpha),
+            width: self.outline_width.unwrap_or(default.width),
+            path_ends: self.path_ends.unwrap_or(default.path_ends),
+            label_height: self.label_height.unwrap_or(default.label_height),
+            min_label_px: self.min_label_px.unwrap_or(default.min_label_px),
+            pattern_spacing_px: self
+                .pattern_spacing_px
+                .unwrap_or(default.pattern_spacing_px),
+            max_zoom_level: self.max_zoom_level.unwrap_or(default.max_zoom_level),
+            pyramid_cache_tiles: self
+                .pyramid_cache_tiles
+                .unwrap_or(default.pyramid_cache_tiles),
+            live_view: self.live_view,
+            live_view_settle_secs: self
+                .live_view_settle_ms
+                .map(|ms| ms as f32 / 1000.0)
+                .unwrap_or(default.live_view_settle_secs),
+            batch_size: self
+                .batch_size
+                .unwrap_or(default.batch_size)
+                .clamp(1, MAX_BATCH_SIZE),
+            tessellation_cache_bytes: self
+                .tessellation_cache_mb
+                .map(|mb| mb << 20)
+                .unwrap_or(default.tessellation_cache_bytes),
+        }
+    }
+
+    /// Whether shapes on layer number `layernum` pass the `--layers` filter
+    pub fn layer_enabled(&self, layernum: i16) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layernum)
+    }
+}
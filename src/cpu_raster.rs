@@ -0,0 +1,495 @@
+use std::error::Error;
+
+use bevy::prelude::{info, ClearColor, Color};
+use layout21::raw;
+
+use crate::{
+    cli::CliArgs,
+    fill_pattern::{contains, FillPattern},
+    import::import_library,
+    load_layout,
+    lyp::LayerProperties,
+    path_to_poly::PathEnds,
+    tiled_renderer::world_outline,
+    types::{
+        FlattenedElems, GeoRect, LayerColors, LayerStyle, Layers, LibLayers, Tile,
+        TiledRendererSettings, Tilemap,
+    },
+    utils::get_grid_shape,
+};
+
+/// An sRGB Rgba8 image, top row first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [
+            self.data[i],
+            self.data[i + 1],
+            self.data[i + 2],
+            self.data[i + 3],
+        ]
+    }
+
+    /// Copy `src` into this image with its top left corner at (`x`, `y`)
+    pub fn blit(&mut self, src: &RgbaImage, x: u32, y: u32) {
+        let row_len = (src.width * 4) as usize;
+        for row in 0..src.height {
+            let src_start = (row * src.width * 4) as usize;
+            let dst_start = (((y + row) * self.width + x) * 4) as usize;
+            self.data[dst_start..dst_start + row_len]
+                .copy_from_slice(&src.data[src_start..src_start + row_len]);
+        }
+    }
+}
+
+/// A square tile being rasterized, kept in linear rgba so blending matches the GPU which blends
+/// into an sRGB texture
+struct TileCanvas {
+    size: u32,
+    pixels: Vec<[f32; 4]>,
+}
+
+impl TileCanvas {
+    fn new(size: u32, background: Color) -> Self {
+        Self {
+            size,
+            pixels: vec![background.as_linear_rgba_f32(); (size * size) as usize],
+        }
+    }
+
+    /// Alpha blend `color` (linear rgba) over the pixel at (`x`, `y`)
+    fn blend(&mut self, x: u32, y: u32, color: [f32; 4]) {
+        let dst = &mut self.pixels[(y * self.size + x) as usize];
+        let a = color[3];
+        for c in 0..3 {
+            dst[c] = color[c] * a + dst[c] * (1.0 - a);
+        }
+        dst[3] = a + dst[3] * (1.0 - a);
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let mut image = RgbaImage::new(self.size, self.size);
+        for (px, out) in self.pixels.iter().zip(image.data.chunks_exact_mut(4)) {
+            let srgb = Color::rgba_linear(px[0], px[1], px[2], px[3]).as_rgba_f32();
+            for c in 0..4 {
+                out[c] = (srgb[c].clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+        image
+    }
+}
+
+/// Outline of `el` in world coordinates, the same as `spawn_shapes_system` draws. Paths that
+/// can't be converted have an empty outline and so aren't drawn, like on the GPU.
+fn element_outline(el: &raw::Element, path_ends: PathEnds) -> Vec<(f64, f64)> {
+    world_outline(el, path_ends).unwrap_or_default()
+}
+
+fn distance_to_segment((px, py): (f64, f64), (x0, y0): (f64, f64), (x1, y1): (f64, f64)) -> f64 {
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let len_sq = dx * dx + dy * dy;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((px - x0) * dx + (py - y0) * dy) / len_sq).clamp(0.0, 1.0)
+    };
+    let (cx, cy) = (x0 + t * dx, y0 + t * dy);
+    ((px - cx).powi(2) + (py - cy).powi(2)).sqrt()
+}
+
+/// Rasterize `shapes` (world space outlines with their layer style, in draw order) that fall in
+/// the world space `extents` into a tile. Every shape is filled with its fill colour at the
+/// settings' `alpha`, or covered by its fill pattern in the opaque fill colour, and outlined in
+/// its frame colour with the settings' `width`, which is in hi-res texture pixels and so is scaled
+/// down by the same factor as the downscaling pass. Outlines thinner than a pixel are blended in
+/// with an alpha proportional to their width, approximating what the downscaling pass samples.
+pub fn rasterize_shapes<'a>(
+    extents: &GeoRect,
+    settings: &TiledRendererSettings,
+    shapes: impl Iterator<Item = (&'a [(f64, f64)], &'a LayerStyle)>,
+) -> RgbaImage {
+    let size = settings.tile_size_in_px;
+
+    let mut canvas = TileCanvas::new(size, ClearColor::default().0);
+
+    let xmin = extents.min().x as f64;
+    let ymax = extents.max().y as f64;
+    let scale = size as f64 / extents.width() as f64;
+
+    let line_width = (settings.width * size as f32 / settings.texture_dim() as f32) as f64;
+    let stroke_reach = (line_width / 2.0).max(0.5);
+    let stroke_alpha = line_width.min(1.0) as f32;
+
+    let pattern_spacing = settings.pattern_spacing(extents.width() as f64);
+
+    for (outline, style) in shapes {
+        if outline.len() < 3 {
+            continue;
+        }
+
+        // into tile pixel space, y down
+        let outline = outline
+            .iter()
+            .map(|&(x, y)| ((x - xmin) * scale, (ymax - y) * scale))
+            .collect::<Vec<(f64, f64)>>();
+
+        let (mut bx0, mut by0, mut bx1, mut by1) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        for &(x, y) in outline.iter() {
+            bx0 = bx0.min(x);
+            by0 = by0.min(y);
+            bx1 = bx1.max(x);
+            by1 = by1.max(y);
+        }
+
+        let px0 = (bx0 - stroke_reach).floor().max(0.0) as u32;
+        let py0 = (by0 - stroke_reach).floor().max(0.0) as u32;
+        let px1 = ((bx1 + stroke_reach).ceil().max(0.0) as u32).min(size);
+        let py1 = ((by1 + stroke_reach).ceil().max(0.0) as u32).min(size);
+
+        let mut fill = style.fill.as_linear_rgba_f32();
+        let pattern = style.fill_pattern;
+        if pattern == FillPattern::Solid {
+            fill[3] = settings.alpha;
+        }
+        let mut stroke = style.frame.as_linear_rgba_f32();
+        stroke[3] = stroke_alpha;
+
+        for py in py0..py1 {
+            for px in px0..px1 {
+                let center = (px as f64 + 0.5, py as f64 + 0.5);
+
+                if contains(&outline, center.0, center.1) {
+                    let world = (xmin + center.0 / scale, ymax - center.1 / scale);
+                    if pattern.covers(world, pattern_spacing) {
+                        canvas.blend(px, py, fill);
+                    }
+                }
+
+                let on_edge = (0..outline.len()).any(|i| {
+                    let next = outline[(i + 1) % outline.len()];
+                    distance_to_segment(center, outline[i], next) <= stroke_reach
+                });
+
+                if on_edge {
+                    canvas.blend(px, py, stroke);
+                }
+            }
+        }
+    }
+
+    canvas.into_image()
+}
+
+/// Rasterize a single tile of the tilemap into a `tile_size_in_px`x`tile_size_in_px` image,
+/// drawing shapes in layer stack order like the z ordering of the GPU path and leaving out layers that
+/// aren't visible
+pub fn rasterize_tile(
+    tile: &Tile,
+    flattened_elems: &FlattenedElems,
+    lib_layers: &LibLayers,
+    layers: &Layers,
+    settings: &TiledRendererSettings,
+) -> RgbaImage {
+    let mut shapes = tile
+        .shapes
+        .iter()
+        .filter_map(|idx| {
+            let el = &flattened_elems[*idx];
+
+            let style = layers.get(&lib_layers.layer_id(el)).unwrap();
+
+            // there's no way to toggle layers on the CPU, so hidden layers stay hidden
+            style
+                .visible
+                .then(|| (element_outline(el, settings.path_ends), style))
+        })
+        .collect::<Vec<(Vec<(f64, f64)>, &LayerStyle)>>();
+
+    // stable, so shapes on the same layer keep their order
+    shapes.sort_by(|(_, a), (_, b)| a.z.total_cmp(&b.z));
+
+    rasterize_shapes(
+        &tile.extents,
+        settings,
+        shapes
+            .iter()
+            .map(|(outline, style)| (outline.as_slice(), *style)),
+    )
+}
+
+/// Rasterize every tile of the tilemap and assemble them into one overview image, with tile
+/// (0, 0) in the bottom left corner
+pub fn rasterize_overview(
+    tilemap: &Tilemap,
+    flattened_elems: &FlattenedElems,
+    lib_layers: &LibLayers,
+    layers: &Layers,
+    settings: &TiledRendererSettings,
+) -> RgbaImage {
+    let (nx, ny) = get_grid_shape(tilemap);
+    let tile_size = settings.tile_size_in_px;
+
+    let mut overview = RgbaImage::new(nx * tile_size, ny * tile_size);
+
+    for (&(ix, iy), tile) in tilemap.iter() {
+        let tile_image = rasterize_tile(tile, flattened_elems, lib_layers, layers, settings);
+        overview.blit(&tile_image, ix * tile_size, (ny - 1 - iy) * tile_size);
+    }
+
+    overview
+}
+
+/// Open the library, rasterize its overview entirely on the CPU and save it to `--png`. Text
+/// labels are only drawn by the GPU renderer.
+pub fn render_overview_png(args: &CliArgs) -> Result<(), Box<dyn Error>> {
+    let lib = import_library(&args.input)?;
+
+    let settings = args.renderer_settings();
+
+    let layer_properties = match &args.lyp {
+        Some(path) => LayerProperties::load(path)?,
+        None => LayerProperties::default(),
+    };
+
+    let loaded = load_layout(
+        &lib,
+        args,
+        &settings,
+        &layer_properties,
+        &mut LayerColors::default(),
+    )?;
+
+    let t = std::time::Instant::now();
+
+    let overview = rasterize_overview(
+        &loaded.tilemap,
+        &loaded.flattened_elems,
+        &loaded.lib_layers,
+        &loaded.layers,
+        &settings,
+    );
+
+    info!("rasterized overview on the CPU in {:?}", t.elapsed());
+
+    let path = args.png.as_ref().unwrap();
+
+    image::save_buffer(
+        path,
+        &overview.data,
+        overview.width,
+        overview.height,
+        image::ColorType::Rgba8,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Vec2;
+
+    use super::*;
+    use crate::{
+        fill_pattern::Diagonal,
+        tiled_renderer::{
+            fill_pattern_shape, outlined_polygon,
+            tests::{lyon_triangles, triangle_covers},
+        },
+        types::{WorldToRender, LAYER_Z_RANGE},
+    };
+
+    fn rect(xmin: f64, ymin: f64, xmax: f64, ymax: f64) -> Vec<(f64, f64)> {
+        vec![(xmin, ymin), (xmax, ymin), (xmax, ymax), (xmin, ymax)]
+    }
+
+    /// What the GPU draws for `shapes` into a tile whose hi-res texture is as large as the tile
+    /// is in database units: the lyon shapes `spawn_shapes_system` spawns for their outlines and
+    /// fill patterns, blended in z order at the pixel centers they cover
+    fn gpu_tile(
+        extents: &GeoRect,
+        settings: &TiledRendererSettings,
+        shapes: &[(Vec<(f64, f64)>, LayerStyle)],
+    ) -> RgbaImage {
+        let world_to_render = WorldToRender::default().at_tile(extents);
+
+        let mut drawn = vec![];
+
+        for (outline, style) in shapes {
+            let fill_alpha = match style.fill_pattern {
+                FillPattern::Solid => settings.alpha,
+                _ => 0.0,
+            };
+
+            let shape = outlined_polygon(
+                outline.iter().map(|&p| world_to_render.point(p)).collect(),
+                style.z,
+                *style.fill.clone().set_a(fill_alpha),
+                style.frame,
+                settings.width,
+            );
+            let pattern = fill_pattern_shape(outline, style, extents, &world_to_render, settings);
+
+            for shape in std::iter::once(shape).chain(pattern) {
+                let z = shape.transform.translation.z;
+                drawn.extend(
+                    lyon_triangles(&shape)
+                        .into_iter()
+                        .map(|(triangles, color)| (z, triangles, color)),
+                );
+            }
+        }
+
+        // stable, like the sort of bevy's transparent 2d phase
+        drawn.sort_by(|(a, ..), (b, ..)| a.total_cmp(b));
+
+        let size = settings.tile_size_in_px;
+        let mut canvas = TileCanvas::new(size, ClearColor::default().0);
+
+        for (_, triangles, color) in drawn {
+            for py in 0..size {
+                for px in 0..size {
+                    // the canvas is y down
+                    let p = Vec2::new(px as f32 + 0.5, (size - py) as f32 - 0.5);
+                    if triangles.iter().any(|&t| triangle_covers(t, p)) {
+                        canvas.blend(px, py, color.as_linear_rgba_f32());
+                    }
+                }
+            }
+        }
+
+        canvas.into_image()
+    }
+
+    #[test]
+    fn shapes_match_the_gpu_in_every_style() {
+        // no downscaling, and outlines and dots on whole pixels and hatch lines on diagonals
+        // that miss every pixel center
+        let settings = TiledRendererSettings {
+            num_tiles: 1,
+            tile_size_in_px: 64,
+            width: 2.0,
+            pattern_spacing_px: 8.0,
+            ..Default::default()
+        };
+        let extents = GeoRect::new((0, 0), (64, 64));
+
+        // an L on the bottom layer, partly under a rectangle on the top one
+        let l = vec![
+            (8.0, 8.0),
+            (40.0, 8.0),
+            (40.0, 24.0),
+            (24.0, 24.0),
+            (24.0, 48.0),
+            (8.0, 48.0),
+        ];
+        let top = LayerStyle {
+            fill: Color::rgb(0.0, 0.0, 1.0),
+            frame: Color::rgb(0.0, 0.0, 0.5),
+            fill_pattern: FillPattern::Solid,
+            visible: true,
+            z: LAYER_Z_RANGE / 2.0,
+        };
+
+        let mut tiles = vec![];
+
+        for fill_pattern in [
+            FillPattern::Solid,
+            FillPattern::Hollow,
+            FillPattern::Hatch(Diagonal::Rising),
+            FillPattern::Hatch(Diagonal::Falling),
+            FillPattern::CrossHatch,
+            FillPattern::Dots,
+        ] {
+            let bottom = LayerStyle {
+                fill: Color::rgb(1.0, 0.0, 0.0),
+                frame: Color::rgb(0.5, 0.0, 0.0),
+                fill_pattern,
+                visible: true,
+                z: 0.0,
+            };
+            let shapes = [
+                (l.clone(), bottom),
+                (rect(20.0, 16.0, 56.0, 40.0), top.clone()),
+            ];
+
+            let cpu = rasterize_shapes(
+                &extents,
+                &settings,
+                shapes
+                    .iter()
+                    .map(|(outline, style)| (outline.as_slice(), style)),
+            );
+            let gpu = gpu_tile(&extents, &settings, &shapes);
+
+            let differing = (0..64)
+                .flat_map(|y| (0..64).map(move |x| (x, y)))
+                .filter(|&(x, y)| cpu.pixel(x, y) != gpu.pixel(x, y))
+                .collect::<Vec<(u32, u32)>>();
+            assert!(
+                differing.is_empty(),
+                "{fill_pattern:?} differs at {differing:?}"
+            );
+
+            tiles.push(cpu);
+        }
+
+        // every style shows
+        for (i, tile) in tiles.iter().enumerate() {
+            assert!(!tiles[..i].contains(tile));
+        }
+    }
+
+    #[test]
+    fn rect_fills_expected_pixels() {
+        let settings = TiledRendererSettings::default();
+        let extents = GeoRect::new((0, 0), (64, 64));
+        let outline = rect(16.0, 0.0, 48.0, 32.0);
+        let red = LayerStyle::from_color(Color::rgb(1.0, 0.0, 0.0));
+
+        let empty = rasterize_shapes(&extents, &settings, std::iter::empty());
+        let tile = rasterize_shapes(
+            &extents,
+            &settings,
+            std::iter::once((outline.as_slice(), &red)),
+        );
+
+        // inside the rect, which covers the bottom half of the middle columns
+        let inside = tile.pixel(32, 48);
+        let background = empty.pixel(32, 48);
+        assert!(inside[0] > background[0]);
+        assert!(inside[1] < background[1]);
+
+        // outside the rect is untouched
+        assert_eq!(tile.pixel(32, 8), empty.pixel(32, 8));
+        assert_eq!(tile.pixel(4, 48), empty.pixel(4, 48));
+    }
+
+    #[test]
+    fn overview_blit_places_tiles() {
+        let mut overview = RgbaImage::new(4, 4);
+        let mut tile = RgbaImage::new(2, 2);
+        tile.data.fill(255);
+
+        overview.blit(&tile, 2, 0);
+
+        assert_eq!(overview.pixel(2, 0), [255; 4]);
+        assert_eq!(overview.pixel(3, 1), [255; 4]);
+        assert_eq!(overview.pixel(1, 0), [0; 4]);
+        assert_eq!(overview.pixel(2, 2), [0; 4]);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{f64::consts::SQRT_2, str::FromStr};
+
+use crate::types::GeoRect;
+
+/// Fraction of the pattern spacing covered by a hatch line, and the side of a dot
+const PATTERN_LINE_FRACTION: f64 = 0.25;
+
+/// How the inside of the shapes on a layer is filled. Hatches and dots are laid out on a lattice
+/// anchored at the world origin so that they line up across shapes and tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillPattern {
+    /// Flat fill at the settings' `alpha`
+    Solid,
+    /// Outline only
+    Hollow,
+    /// Lines at 45 degrees along one diagonal
+    Hatch(Diagonal),
+    /// Lines rising and falling at 45 degrees
+    CrossHatch,
+    /// Square dots on a grid
+    Dots,
+}
+
+impl Default for FillPattern {
+    fn default() -> Self {
+        FillPattern::Solid
+    }
+}
+
+impl FromStr for FillPattern {
+    type Err = String;
+
+    /// Parses `solid`, `hollow`, `hatch` (rising), `back-hatch` (falling), `cross-hatch` or
+    /// `dots`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "solid" => Ok(FillPattern::Solid),
+            "hollow" => Ok(FillPattern::Hollow),
+            "hatch" => Ok(FillPattern::Hatch(Diagonal::Rising)),
+            "back-hatch" => Ok(FillPattern::Hatch(Diagonal::Falling)),
+            "cross-hatch" => Ok(FillPattern::CrossHatch),
+            "dots" => Ok(FillPattern::Dots),
+            _ => Err(format!(
+                "expected solid, hollow, hatch, back-hatch, cross-hatch or dots, found {s:?}"
+            )),
+        }
+    }
+}
+
+/// Direction of the lines of a hatch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagonal {
+    Rising,
+    Falling,
+}
+
+impl Diagonal {
+    /// The coordinate that is constant along a line, and the one that runs along it
+    fn to_line_space(self, (x, y): (f64, f64)) -> (f64, f64) {
+        match self {
+            Diagonal::Rising => (y - x, x + y),
+            Diagonal::Falling => (x + y, x - y),
+        }
+    }
+
+    fn from_line_space(self, (c, s): (f64, f64)) -> (f64, f64) {
+        match self {
+            Diagonal::Rising => ((s - c) / 2.0, (s + c) / 2.0),
+            Diagonal::Falling => ((c + s) / 2.0, (c - s) / 2.0),
+        }
+    }
+}
+
+impl FillPattern {
+    /// The closest of our patterns to a KLayout dither pattern, e.g. `I9`. KLayout has many
+    /// more patterns, the dotted ones become dots, the hatched ones hatches along the same
+    /// diagonal, `I4` to `I7` falling and `I8` to `I11` rising, the cross-hatched ones
+    /// cross-hatches, and anything else a solid fill.
+    pub fn from_dither_pattern(name: &str) -> Self {
+        match name {
+            "I0" => FillPattern::Solid,
+            "I1" => FillPattern::Hollow,
+            "I2" | "I3" => FillPattern::Dots,
+            "I4" | "I5" | "I6" | "I7" => FillPattern::Hatch(Diagonal::Falling),
+            "I8" | "I9" | "I10" | "I11" => FillPattern::Hatch(Diagonal::Rising),
+            "I12" | "I13" | "I15" => FillPattern::CrossHatch,
+            _ => FillPattern::Solid,
+        }
+    }
+
+    /// The diagonals of the lines making up the pattern
+    pub fn diagonals(&self) -> &'static [Diagonal] {
+        match self {
+            FillPattern::Hatch(Diagonal::Rising) => &[Diagonal::Rising],
+            FillPattern::Hatch(Diagonal::Falling) => &[Diagonal::Falling],
+            FillPattern::CrossHatch => &[Diagonal::Rising, Diagonal::Falling],
+            _ => &[],
+        }
+    }
+
+    /// Width of hatch lines and side of dots for a pattern `spacing` apart
+    pub fn line_width(spacing: f64) -> f64 {
+        spacing * PATTERN_LINE_FRACTION
+    }
+
+    /// Whether the world space point `p` inside a shape is covered by the pattern with its
+    /// lines or dots `spacing` apart. This is what the geometry from `hatch_segments` and
+    /// `dot_centers` covers, so the CPU renderer can test pixels instead of drawing it.
+    pub fn covers(&self, p: (f64, f64), spacing: f64) -> bool {
+        let half_width = Self::line_width(spacing) / 2.0;
+
+        match self {
+            FillPattern::Solid => true,
+            FillPattern::Hollow => false,
+            FillPattern::Hatch(_) | FillPattern::CrossHatch => {
+                let pitch = spacing * SQRT_2;
+                self.diagonals().iter().any(|diagonal| {
+                    let r = diagonal.to_line_space(p).0.rem_euclid(pitch);
+                    r.min(pitch - r) / SQRT_2 <= half_width
+                })
+            }
+            FillPattern::Dots => {
+                let offset = |v: f64| (v - (v / spacing).round() * spacing).abs();
+                offset(p.0) <= half_width && offset(p.1) <= half_width
+            }
+        }
+    }
+}
+
+/// Even-odd point in polygon test
+pub fn contains(outline: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    let mut j = outline.len() - 1;
+    for i in 0..outline.len() {
+        let (xi, yi) = outline[i];
+        let (xj, yj) = outline[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Bounds of `outline` limited to `window`, or `None` if they don't overlap
+fn clipped_bounds(outline: &[(f64, f64)], window: &GeoRect) -> Option<((f64, f64), (f64, f64))> {
+    let (mut x0, mut y0, mut x1, mut y1) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for &(x, y) in outline.iter() {
+        x0 = x0.min(x);
+        y0 = y0.min(y);
+        x1 = x1.max(x);
+        y1 = y1.max(y);
+    }
+
+    let (min, max) = (window.min(), window.max());
+    let (x0, y0) = (x0.max(min.x as f64), y0.max(min.y as f64));
+    let (x1, y1) = (x1.min(max.x as f64), y1.min(max.y as f64));
+
+    (x0 <= x1 && y0 <= y1).then(|| ((x0, y0), (x1, y1)))
+}
+
+/// The pieces inside `outline` of the hatch lines along `diagonal` that are `spacing` apart,
+/// limited to the lines crossing `window`. Segments can reach outside `window`, which the
+/// camera of a tile clips.
+pub fn hatch_segments(
+    outline: &[(f64, f64)],
+    spacing: f64,
+    diagonal: Diagonal,
+    window: &GeoRect,
+) -> Vec<((f64, f64), (f64, f64))> {
+    let mut segments = vec![];
+
+    if outline.len() < 3 {
+        return segments;
+    }
+
+    let ((x0, y0), (x1, y1)) = match clipped_bounds(outline, window) {
+        Some(bounds) => bounds,
+        None => return segments,
+    };
+
+    let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|p| diagonal.to_line_space(p).0);
+    let c_min = corners.iter().copied().fold(f64::MAX, f64::min);
+    let c_max = corners.iter().copied().fold(f64::MIN, f64::max);
+
+    let pitch = spacing * SQRT_2;
+
+    let outline = outline
+        .iter()
+        .map(|&p| diagonal.to_line_space(p))
+        .collect::<Vec<(f64, f64)>>();
+
+    let mut crossings = vec![];
+
+    for k in (c_min / pitch).ceil() as i64..=(c_max / pitch).floor() as i64 {
+        let c = k as f64 * pitch;
+
+        crossings.clear();
+
+        for i in 0..outline.len() {
+            let (cp, sp) = outline[i];
+            let (cq, sq) = outline[(i + 1) % outline.len()];
+
+            if (cp < c) != (cq < c) {
+                let t = (c - cp) / (cq - cp);
+                crossings.push(sp + t * (sq - sp));
+            }
+        }
+
+        crossings.sort_by(f64::total_cmp);
+
+        for pair in crossings.chunks_exact(2) {
+            segments.push((
+                diagonal.from_line_space((c, pair[0])),
+                diagonal.from_line_space((c, pair[1])),
+            ));
+        }
+    }
+
+    segments
+}
+
+/// Centers of the dots `spacing` apart that fall inside `outline` and `window`
+pub fn dot_centers(outline: &[(f64, f64)], spacing: f64, window: &GeoRect) -> Vec<(f64, f64)> {
+    let mut centers = vec![];
+
+    if outline.len() < 3 {
+        return centers;
+    }
+
+    let ((x0, y0), (x1, y1)) = match clipped_bounds(outline, window) {
+        Some(bounds) => bounds,
+        None => return centers,
+    };
+
+    for j in (y0 / spacing).ceil() as i64..=(y1 / spacing).floor() as i64 {
+        for i in (x0 / spacing).ceil() as i64..=(x1 / spacing).floor() as i64 {
+            let (x, y) = (i as f64 * spacing, j as f64 * spacing);
+            if contains(outline, x, y) {
+                centers.push((x, y));
+            }
+        }
+    }
+
+    centers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(size: f64) -> Vec<(f64, f64)> {
+        vec![(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)]
+    }
+
+    #[test]
+    fn hatch_segments_span_the_shape() {
+        let window = GeoRect::new((-100, -100), (100, 100));
+        let segments = hatch_segments(&square(10.0), SQRT_2, Diagonal::Rising, &window);
+
+        // lines y - x = 2k crossing the square, for k in -4..=4
+        assert_eq!(segments.len(), 9);
+
+        for &((xa, ya), (xb, yb)) in segments.iter() {
+            // along the rising diagonal
+            assert!((yb - ya - (xb - xa)).abs() < 1e-9);
+            // ends on the boundary of the square
+            for (x, y) in [(xa, ya), (xb, yb)] {
+                let on_edge = x.abs() < 1e-9
+                    || y.abs() < 1e-9
+                    || (x - 10.0).abs() < 1e-9
+                    || (y - 10.0).abs() < 1e-9;
+                assert!(on_edge, "({x}, {y}) is not on the square");
+            }
+        }
+
+        // the diagonal itself
+        assert!(segments.contains(&((0.0, 0.0), (10.0, 10.0))));
+    }
+
+    #[test]
+    fn patterns_are_limited_to_the_window() {
+        let window = GeoRect::new((20, 20), (30, 30));
+        assert!(hatch_segments(&square(10.0), 1.0, Diagonal::Falling, &window).is_empty());
+        assert!(dot_centers(&square(10.0), 1.0, &window).is_empty());
+
+        // (2, 2), (4, 2), (2, 4) and (4, 4)
+        let window = GeoRect::new((1, 1), (5, 5));
+        assert_eq!(dot_centers(&square(10.0), 2.0, &window).len(), 4);
+    }
+
+    #[test]
+    fn covers_matches_geometry() {
+        let spacing = 4.0;
+        let half_width = FillPattern::line_width(spacing) / 2.0;
+
+        let rising = FillPattern::Hatch(Diagonal::Rising);
+        let falling = FillPattern::Hatch(Diagonal::Falling);
+
+        // on a rising line, and just off it
+        assert!(rising.covers((1.0, 1.0), spacing));
+        assert!(!rising.covers((1.0, 1.0 + 4.0 * half_width), spacing));
+
+        // the falling lines are in the falling hatch and the cross-hatch only
+        assert!(!rising.covers((1.0, -1.0), spacing));
+        assert!(falling.covers((1.0, -1.0), spacing));
+        assert!(!falling.covers((1.0, 1.0 + 4.0 * half_width), spacing));
+        assert!(FillPattern::CrossHatch.covers((1.0, -1.0), spacing));
+
+        assert!(FillPattern::Dots.covers((8.0, -4.0 + half_width), spacing));
+        assert!(!FillPattern::Dots.covers((2.0, 2.0), spacing));
+
+        assert!(FillPattern::Solid.covers((2.0, 2.0), spacing));
+        assert!(!FillPattern::Hollow.covers((2.0, 2.0), spacing));
+    }
+
+    #[test]
+    fn dither_patterns() {
+        assert_eq!(FillPattern::from_dither_pattern("I0"), FillPattern::Solid);
+        assert_eq!(FillPattern::from_dither_pattern("I1"), FillPattern::Hollow);
+        assert_eq!(
+            FillPattern::from_dither_pattern("I5"),
+            FillPattern::Hatch(Diagonal::Falling)
+        );
+        assert_eq!(
+            FillPattern::from_dither_pattern("I9"),
+            FillPattern::Hatch(Diagonal::Rising)
+        );
+        assert_eq!(
+            FillPattern::from_dither_pattern("I13"),
+            FillPattern::CrossHatch
+        );
+        assert_eq!(FillPattern::from_dither_pattern("I2"), FillPattern::Dots);
+        assert_eq!(FillPattern::from_dither_pattern("C3"), FillPattern::Solid);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use layout21::{
+    gds21::GdsLibrary,
+    raw::{self, gds::GdsImporter, proto::ProtoImporter, Library},
+};
+
+/// Every GDSII stream starts with a HEADER record: length 6, record type 0x00, data type 0x02
+const GDS_HEADER_RECORD: [u8; 4] = [0x00, 0x06, 0x00, 0x02];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryFormat {
+    Gds,
+    VlsirProto,
+}
+
+impl LibraryFormat {
+    /// Detect the format of the library at `path`, first by file extension and then by
+    /// sniffing the leading bytes for a GDSII HEADER record. Anything that isn't GDSII is
+    /// assumed to be a VLSIR protobuf.
+    pub fn detect(path: &Path) -> LibraryFormat {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("gds" | "gds2" | "gdsii" | "strm") => return LibraryFormat::Gds,
+            Some("proto" | "pb") => return LibraryFormat::VlsirProto,
+            _ => {}
+        }
+
+        let mut header = [0u8; 4];
+        if let Ok(mut file) = File::open(path) {
+            if file.read_exact(&mut header).is_ok() && header == GDS_HEADER_RECORD {
+                return LibraryFormat::Gds;
+            }
+        }
+
+        LibraryFormat::VlsirProto
+    }
+}
+
+/// Error details reported by layout21/gds21/vlsir, which we only have a `Debug` representation of
+#[derive(Debug)]
+pub struct ErrorDetail(pub String);
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ErrorDetail {}
+
+#[derive(Debug)]
+pub enum LibraryOpenError {
+    /// The file could not be read at all, e.g. a typo'd path
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file was read but is not a valid GDSII stream or VLSIR protobuf
+    Parse {
+        path: PathBuf,
+        format: LibraryFormat,
+        source: ErrorDetail,
+    },
+    /// The file was parsed but could not be converted into a `raw::Library`
+    Import {
+        path: PathBuf,
+        format: LibraryFormat,
+        source: ErrorDetail,
+    },
+    /// The library was imported but has no cells to render
+    NoCells,
+    /// `--top-cell` names a cell the library doesn't have
+    NoSuchCell {
+        name: String,
+        available: Vec<String>,
+    },
+    /// The cell to render has no layout, only an abstract or a netlist
+    NoLayout { cell: String },
+    /// The layout of the cell couldn't be flattened into its elements
+    Flatten { cell: String, source: ErrorDetail },
+    /// The cell has no shapes left to render, e.g. none on the `--layers` it is filtered to
+    NoShapes { cell: String },
+}
+
+impl fmt::Display for LibraryOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibraryOpenError::Io { path, .. } => write!(f, "could not read {path:?}"),
+            LibraryOpenError::Parse { path, format, .. } => {
+                write!(f, "could not parse {path:?} as {format:?}")
+            }
+            LibraryOpenError::Import { path, format, .. } => {
+                write!(
+                    f,
+                    "could not import {path:?} ({format:?}) as a layout library"
+                )
+            }
+            LibraryOpenError::NoCells => write!(f, "the library has no cells"),
+            LibraryOpenError::NoSuchCell { name, available } => {
+                write!(
+                    f,
+                    "cell {name} does not exist in the library, available cells: {available:?}"
+                )
+            }
+            LibraryOpenError::NoLayout { cell } => write!(f, "cell {cell} has no layout"),
+            LibraryOpenError::Flatten { cell, .. } => {
+                write!(f, "could not flatten the layout of cell {cell}")
+            }
+            LibraryOpenError::NoShapes { cell } => write!(f, "cell {cell} has no shapes to render"),
+        }
+    }
+}
+
+impl Error for LibraryOpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LibraryOpenError::Io { source, .. } => Some(source),
+            LibraryOpenError::Parse { source, .. }
+            | LibraryOpenError::Import { source, .. }
+            | LibraryOpenError::Flatten { source, .. } => Some(source),
+            LibraryOpenError::NoCells
+            | LibraryOpenError::NoSuchCell { .. }
+            | LibraryOpenError::NoLayout { .. }
+            | LibraryOpenError::NoShapes { .. } => None,
+        }
+    }
+}
+
+impl LibraryOpenError {
+    /// This error followed by each of its sources, outermost first
+    pub fn chain(&self) -> Vec<String> {
+        let mut chain = vec![self.to_string()];
+        let mut source = self.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        chain
+    }
+}
+
+/// Open the library at `path` and import it into a `raw::Library`, regardless of whether it
+/// is a GDSII stream or a VLSIR protobuf
+pub fn import_library(path: &Path) -> Result<Library, LibraryOpenError> {
+    // check up front so a missing file is reported as such rather than as a parse failure
+    std::fs::metadata(path).map_err(|source| LibraryOpenError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let format = LibraryFormat::detect(path);
+
+    info!("importing {path:?} as {format:?}");
+
+    let parse_err = |e: &dyn fmt::Debug| LibraryOpenError::Parse {
+        path: path.to_path_buf(),
+        format,
+        source: ErrorDetail(format!("{e:?}")),
+    };
+
+    let import_err = |e: &dyn fmt::Debug| LibraryOpenError::Import {
+        path: path.to_path_buf(),
+        format,
+        source: ErrorDetail(format!("{e:?}")),
+    };
+
+    match format {
+        LibraryFormat::Gds => {
+            let gds_lib = GdsLibrary::load(path).map_err(|e| parse_err(&e))?;
+            GdsImporter::import(&gds_lib, None).map_err(|e| import_err(&e))
+        }
+        LibraryFormat::VlsirProto => {
+            let plib = raw::proto::proto::open(path).map_err(|e| parse_err(&e))?;
+            ProtoImporter::import(&plib, None).map_err(|e| import_err(&e))
+        }
+    }
+}
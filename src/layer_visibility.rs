@@ -0,0 +1,151 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::types::{
+    FlattenedElems, HiddenLayers, LayerId, LayerTiles, Layers, LibLayers, MainView, TileIndexIter,
+    TiledRendererSettings, Tilemap,
+};
+
+/// Toggles the visibility of layers from the keyboard and re-renders the tiles holding shapes
+/// on a toggled layer:
+///
+/// - `[` and `]` select the previous and next layer
+/// - `V` toggles the visibility of the selected layer
+/// - `A` shows every layer
+pub struct LayerVisibilityPlugin;
+
+impl Plugin for LayerVisibilityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HiddenLayers>()
+            .init_resource::<LayerTiles>()
+            .add_system(index_layer_tiles_system)
+            .add_system(toggle_layer_visibility_system);
+    }
+}
+
+/// Rebuild `LayerTiles` whenever a new tilemap is loaded
+fn index_layer_tiles_system(
+    tilemap: Res<Tilemap>,
+    flattened_elems: Res<FlattenedElems>,
+    lib_layers: Res<LibLayers>,
+    mut layer_tiles: ResMut<LayerTiles>,
+) {
+    if !tilemap.is_changed() {
+        return;
+    }
+
+    let mut tiles_by_layer = HashMap::<LayerId, HashSet<(u32, u32)>>::default();
+
+    for (key, tile) in tilemap.iter() {
+        for idx in tile.shapes.iter() {
+            let layer = lib_layers.layer_id(&flattened_elems[*idx]);
+
+            tiles_by_layer.entry(layer).or_default().insert(*key);
+        }
+    }
+
+    layer_tiles.clear();
+
+    for (layer, tiles) in tiles_by_layer {
+        let mut tiles = tiles.into_iter().collect::<Vec<(u32, u32)>>();
+        tiles.sort_by_key(|&(x, y)| (y, x));
+        layer_tiles.insert(layer, tiles);
+    }
+}
+
+fn toggle_layer_visibility_system(
+    keys: Res<Input<KeyCode>>,
+    layers: Res<Layers>,
+    layer_tiles: Res<LayerTiles>,
+    mut hidden_layers: ResMut<HiddenLayers>,
+    mut tile_index_iter: ResMut<TileIndexIter>,
+    main_view: Res<MainView>,
+    settings: Res<TiledRendererSettings>,
+    mut windows: ResMut<Windows>,
+    mut selected: Local<usize>,
+) {
+    let mut layer_ids = layers.keys().copied().collect::<Vec<LayerId>>();
+
+    if layer_ids.is_empty() {
+        return;
+    }
+
+    layer_ids.sort_unstable();
+
+    *selected = (*selected).min(layer_ids.len() - 1);
+
+    let selection_changed = if keys.just_pressed(KeyCode::LBracket) {
+        *selected = (*selected + layer_ids.len() - 1) % layer_ids.len();
+        true
+    } else if keys.just_pressed(KeyCode::RBracket) {
+        *selected = (*selected + 1) % layer_ids.len();
+        true
+    } else {
+        false
+    };
+
+    let layer = layer_ids[*selected];
+
+    let toggled = if keys.just_pressed(KeyCode::V) {
+        if !hidden_layers.remove(&layer) {
+            hidden_layers.insert(layer);
+        }
+        vec![layer]
+    } else if keys.just_pressed(KeyCode::A) {
+        hidden_layers.drain().collect()
+    } else {
+        vec![]
+    };
+
+    for layer in toggled.iter() {
+        if let Some(tiles) = layer_tiles.get(layer) {
+            queue_tiles(
+                &mut tile_index_iter,
+                tiles,
+                &main_view,
+                settings.tile_size_in_px,
+            );
+        }
+    }
+
+    if selection_changed || !toggled.is_empty() {
+        let state = if hidden_layers.contains(&layer) {
+            "hidden"
+        } else {
+            "visible"
+        };
+
+        info!(
+            "layer {layer} selected ({state}), hidden layers: {:?}",
+            **hidden_layers
+        );
+
+        if let Some(window) = windows.get_primary_mut() {
+            window.set_title(format!("Layer {layer} ({state})"));
+        }
+    }
+}
+
+/// Add `tiles` to the render walk, starting a new walk if none is in progress
+fn queue_tiles(
+    tile_index_iter: &mut TileIndexIter,
+    tiles: &[(u32, u32)],
+    main_view: &MainView,
+    tile_size_in_px: u32,
+) {
+    let mut queued = tile_index_iter
+        .take()
+        .map(|remaining| remaining.collect::<Vec<(u32, u32)>>())
+        .unwrap_or_default();
+
+    queued.extend_from_slice(tiles);
+
+    queued.sort_unstable();
+    queued.dedup();
+
+    main_view.order_tiles(&mut queued, tile_size_in_px);
+
+    **tile_index_iter = Some(queued.into_iter());
+}
@@ -0,0 +1,313 @@
+use bevy::{prelude::*, sprite::Anchor, utils::HashSet};
+
+use crate::{
+    bin_shapes, clear_accumulation_image, import_cell_labels, render_target_image,
+    types::{
+        FlattenedElems, FlattenedLabels, HiddenLayers, LabelFont, LiveView, LiveViewSettledEvent,
+        LiveViewSprite, MainCamera, TileGrid, TiledRendererSettings, Tilemap, ViewRect,
+        MAIN_CAMERA_LAYER,
+    },
+};
+
+/// The live view is drawn over the accumulation texture and every level of the pyramid
+const LIVE_VIEW_Z: f32 = 500.0;
+
+/// Re-renders what the main camera shows at the window's resolution once it stops moving, with
+/// the same hi-res camera and downscale pass as every other tile, and shows the result over the
+/// overview. The render is dropped as soon as the camera moves again or the layout changes.
+pub struct LiveViewPlugin;
+
+impl Plugin for LiveViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LiveView>()
+            .add_event::<LiveViewSettledEvent>()
+            .add_system(settle_live_view_system)
+            .add_system(start_live_view_system)
+            .add_system(show_live_view_system);
+    }
+}
+
+/// Tile grid of the live view for `view` of the main view showing `width` x `height` window
+/// pixels, with one texture pixel per window pixel. `overview` is the grid of the accumulation
+/// texture that the main view shows.
+pub fn live_view_grid(
+    view: &ViewRect,
+    overview: &TileGrid,
+    tile_size_in_px: u32,
+    width: u32,
+    height: u32,
+) -> TileGrid {
+    // a live view tile is as many database units across as there are main view units per window
+    // pixel times the database units per accumulation texture pixel, times its size in pixels
+    let view_units_per_px = (view.max.x - view.min.x) / width as f32;
+    let tile_size = ((view_units_per_px * overview.tile_size as f32).round() as u64).max(1);
+
+    // one extra tile along each side for the rounding of the origin and tile size
+    let tiles_along = |px: u32| (px + tile_size_in_px - 1) / tile_size_in_px + 1;
+
+    TileGrid {
+        origin: overview.view_to_world((view.min.x, view.min.y), tile_size_in_px),
+        tile_size,
+        num_x: tiles_along(width),
+        num_y: tiles_along(height),
+    }
+}
+
+/// Cancel the live view whenever what it shows changes, and send `LiveViewSettledEvent` once
+/// the main camera has been still for the settings' `live_view_settle_secs`
+fn settle_live_view_system(
+    time: Res<Time>,
+    settings: Res<TiledRendererSettings>,
+    tilemap: Res<Tilemap>,
+    hidden_layers: Res<HiddenLayers>,
+    mut live_view: ResMut<LiveView>,
+    camera_q: Query<
+        (),
+        (
+            Or<(Changed<Transform>, Changed<OrthographicProjection>)>,
+            With<MainCamera>,
+        ),
+    >,
+    mut sprite_q: Query<&mut Visibility, With<LiveViewSprite>>,
+    mut settled_ev: EventWriter<LiveViewSettledEvent>,
+    mut last_change: Local<Option<f64>>,
+) {
+    if !settings.live_view {
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+
+    // the rendered live view stays aligned while the camera moves, but not over new shapes
+    if tilemap.is_changed() || hidden_layers.is_changed() {
+        for mut vis in sprite_q.iter_mut() {
+            vis.is_visible = false;
+        }
+        live_view.cancel();
+        *last_change = Some(now);
+    }
+
+    if !camera_q.is_empty() {
+        if live_view.rendering {
+            live_view.cancel();
+        }
+        *last_change = Some(now);
+    }
+
+    if let Some(changed_at) = *last_change {
+        if now - changed_at >= settings.live_view_settle_secs as f64 {
+            *last_change = None;
+            settled_ev.send_default();
+        }
+    }
+}
+
+/// Tile what the main camera shows once it settles and queue the tiles with anything in them
+fn start_live_view_system(
+    mut commands: Commands,
+    mut live_view: ResMut<LiveView>,
+    mut images: ResMut<Assets<Image>>,
+    tilemap: Res<Tilemap>,
+    grid: Res<TileGrid>,
+    flattened_elems: Res<FlattenedElems>,
+    flattened_labels: Res<FlattenedLabels>,
+    label_font: Res<LabelFont>,
+    settings: Res<TiledRendererSettings>,
+    clear_color: Res<ClearColor>,
+    windows: Res<Windows>,
+    camera_q: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    mut sprite_q: Query<
+        (&mut Sprite, &mut Transform, &mut Visibility),
+        (With<LiveViewSprite>, Without<MainCamera>),
+    >,
+    mut settled_ev: EventReader<LiveViewSettledEvent>,
+) {
+    if settled_ev.iter().count() == 0 || grid.num_x == 0 || grid.num_y == 0 {
+        return;
+    }
+
+    let (window, (transform, projection)) = match (windows.get_primary(), camera_q.get_single()) {
+        (Some(window), Ok(camera)) => (window, camera),
+        _ => return,
+    };
+
+    let view = ViewRect::from_camera(transform, projection);
+
+    let live_grid = live_view_grid(
+        &view,
+        &grid,
+        settings.tile_size_in_px,
+        window.physical_width(),
+        window.physical_height(),
+    );
+
+    // only the tiles of the overview under the view can hold shapes in it
+    let (min, max) = (
+        live_grid.tile_extents(0, 0).min(),
+        live_grid
+            .tile_extents(live_grid.num_x - 1, live_grid.num_y - 1)
+            .max(),
+    );
+    let (x_range, y_range) = grid.tile_range((min.x, min.y), (max.x, max.y));
+
+    let mut candidates = HashSet::default();
+    for iy in y_range {
+        for ix in x_range.clone() {
+            if let Some(tile) = tilemap.get(&(ix, iy)) {
+                candidates.extend(tile.shapes.iter().copied());
+            }
+        }
+    }
+
+    let mut candidates = candidates.into_iter().collect::<Vec<usize>>();
+    candidates.sort_unstable();
+
+    let mut live_tilemap = live_grid.build_tilemap();
+    let mut shape_count = 0;
+
+    bin_shapes(
+        &live_grid,
+        &mut live_tilemap,
+        &flattened_elems,
+        candidates,
+        settings.path_ends,
+        &mut shape_count,
+    );
+
+    import_cell_labels(
+        &live_grid,
+        &mut live_tilemap,
+        &flattened_labels,
+        settings.label_height,
+    );
+
+    let draw_labels = label_font.0.is_some();
+
+    let mut queue = live_tilemap
+        .iter()
+        .filter(|(_, tile)| !tile.is_empty(draw_labels))
+        .map(|(key, _)| *key)
+        .collect::<Vec<(u32, u32)>>();
+
+    // closest to the centre of the window first
+    let center = (live_grid.num_x as f32 / 2.0, live_grid.num_y as f32 / 2.0);
+    queue.sort_by(|a, b| {
+        let distance =
+            |&(x, y): &(u32, u32)| (x as f32 + 0.5 - center.0).hypot(y as f32 + 0.5 - center.1);
+        distance(a).total_cmp(&distance(b))
+    });
+
+    info!(
+        "live view of {shape_count} shapes in {} of {}x{} tiles",
+        queue.len(),
+        live_grid.num_x,
+        live_grid.num_y
+    );
+
+    // sized to the live grid below
+    let image = live_view
+        .image
+        .get_or_insert_with(|| images.add(render_target_image(1, "LIVE_VIEW_TEXTURE")))
+        .clone();
+
+    // empty tiles are left as cleared here, like in the accumulation texture
+    clear_accumulation_image(
+        images.get_mut(&image).unwrap(),
+        &live_grid,
+        &settings,
+        clear_color.0,
+    );
+
+    let min = live_grid.tile_extents(0, 0).min();
+    let max = live_grid
+        .tile_extents(live_grid.num_x - 1, live_grid.num_y - 1)
+        .max();
+    let (x0, y0) = grid.world_to_view((min.x, min.y), settings.tile_size_in_px);
+    let (x1, y1) = grid.world_to_view((max.x, max.y), settings.tile_size_in_px);
+
+    let existing_sprite = live_view
+        .sprite
+        .and_then(|sprite| sprite_q.get_mut(sprite).ok());
+
+    // hidden until every tile has been rendered
+    if let Some((mut sprite, mut transform, mut vis)) = existing_sprite {
+        sprite.custom_size = Some(Vec2::new(x1 - x0, y1 - y0));
+        transform.translation = Vec3::new(x0, y0, LIVE_VIEW_Z);
+        vis.is_visible = false;
+    } else {
+        let sprite = commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(x1 - x0, y1 - y0)),
+                    anchor: Anchor::BottomLeft,
+                    ..default()
+                },
+                texture: image,
+                transform: Transform::from_translation(Vec3::new(x0, y0, LIVE_VIEW_Z)),
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(MAIN_CAMERA_LAYER)
+            .insert(LiveViewSprite)
+            .id();
+
+        live_view.sprite = Some(sprite);
+    }
+
+    live_view.render += 1;
+    live_view.grid = live_grid;
+    live_view.tilemap = live_tilemap;
+    live_view.remaining = queue.len();
+    live_view.queue = queue.into();
+    live_view.rendering = true;
+}
+
+/// Show the live view once every tile of the current render is done
+fn show_live_view_system(
+    mut live_view: ResMut<LiveView>,
+    mut sprite_q: Query<&mut Visibility, With<LiveViewSprite>>,
+) {
+    if !live_view.rendering || live_view.remaining > 0 {
+        return;
+    }
+
+    live_view.rendering = false;
+
+    for mut vis in sprite_q.iter_mut() {
+        vis.is_visible = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_view_grid_has_a_pixel_per_window_pixel() {
+        // 100 database units per 64 pixel tile in the accumulation texture
+        let overview = TileGrid {
+            origin: (-1000, 500),
+            tile_size: 100,
+            num_x: 16,
+            num_y: 16,
+        };
+
+        // zoomed in so that a window pixel is half an accumulation texture pixel
+        let view = ViewRect {
+            min: Vec2::new(64.0, 128.0),
+            max: Vec2::new(704.0, 448.0),
+        };
+
+        let grid = live_view_grid(&view, &overview, 64, 1280, 640);
+
+        assert_eq!(grid.origin, (-900, 700));
+        // 64 window pixels across a tile, 32 accumulation texture pixels
+        assert_eq!(grid.tile_size, 50);
+        assert_eq!((grid.num_x, grid.num_y), (21, 11));
+
+        // covers the view
+        let far_corner = overview.view_to_world((view.max.x, view.max.y), 64);
+        let extents = grid.tile_extents(grid.num_x - 1, grid.num_y - 1);
+        assert!(extents.max().x >= far_corner.0 && extents.max().y >= far_corner.1);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    fill_pattern::FillPattern,
+    types::{LayerId, LayerStyle},
+};
+
+/// The style of one (layer, datatype) in a KLayout layer properties file. Anything left out of
+/// the file is `None` and filled in from the palette when the layer is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LypLayer {
+    pub fill: Option<Color>,
+    pub frame: Option<Color>,
+    pub dither_pattern: Option<String>,
+    pub visible: bool,
+}
+
+impl LypLayer {
+    /// The layer style, with a missing fill colour taken from `fallback` and a missing frame
+    /// colour following the fill
+    pub fn style(&self, fallback: impl FnOnce() -> Color) -> LayerStyle {
+        let fill = self.fill.unwrap_or_else(fallback);
+
+        LayerStyle {
+            fill,
+            frame: self.frame.unwrap_or(fill),
+            fill_pattern: self
+                .dither_pattern
+                .as_deref()
+                .map(FillPattern::from_dither_pattern)
+                .unwrap_or_default(),
+            visible: self.visible,
+            // placed in the layer stack once the layout is loaded
+            z: 0.0,
+        }
+    }
+}
+
+/// Layer styles read from a KLayout `.lyp` layer properties file, by (layer, datatype)
+#[derive(Debug, Default, Clone, Deref)]
+pub struct LayerProperties(HashMap<LayerId, LypLayer>);
+
+#[derive(Debug)]
+pub enum LypError {
+    /// The file could not be read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is not well formed XML
+    Xml {
+        path: PathBuf,
+        source: roxmltree::Error,
+    },
+}
+
+impl fmt::Display for LypError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LypError::Io { path, .. } => write!(f, "could not read {path:?}"),
+            LypError::Xml { path, .. } => {
+                write!(f, "could not parse {path:?} as a layer properties file")
+            }
+        }
+    }
+}
+
+impl Error for LypError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LypError::Io { source, .. } => Some(source),
+            LypError::Xml { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Text of the first child element of `node` named `name`, if it has any
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+}
+
+/// Parse a `#rrggbb` colour
+fn parse_color(text: &str) -> Option<Color> {
+    Color::hex(text.strip_prefix('#')?).ok()
+}
+
+/// Parse the layer out of a layer source, which is one of `68/20`, `68/20@1` or
+/// `M1 (68/20@1)`. Wildcard and named-only sources aren't supported.
+fn parse_source(source: &str) -> Option<LayerId> {
+    let source = match (source.find('('), source.rfind(')')) {
+        (Some(open), Some(close)) if open < close => &source[open + 1..close],
+        _ => source,
+    };
+
+    source.split('@').next()?.parse().ok()
+}
+
+impl LayerProperties {
+    /// Read the layer properties file at `path`
+    pub fn load(path: &Path) -> Result<Self, LypError> {
+        let xml = std::fs::read_to_string(path).map_err(|source| LypError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        Self::parse(&xml).map_err(|source| LypError::Xml {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Parse the contents of a layer properties file. Every `properties` element with a
+    /// (layer, datatype) source is read, as is every `group-members` element nested in a
+    /// group. Entries whose source can't be parsed are skipped with a warning.
+    pub fn parse(xml: &str) -> Result<Self, roxmltree::Error> {
+        let doc = roxmltree::Document::parse(xml)?;
+
+        let mut layers = HashMap::default();
+
+        for props in doc
+            .descendants()
+            .filter(|node| node.has_tag_name("properties") || node.has_tag_name("group-members"))
+        {
+            let source = match child_text(props, "source") {
+                Some(source) => source,
+                // group headers carry no source of their own
+                None => continue,
+            };
+
+            let key = match parse_source(source) {
+                Some(key) => key,
+                None => {
+                    warn!("skipping layer properties with unsupported source {source:?}");
+                    continue;
+                }
+            };
+
+            let layer = LypLayer {
+                fill: child_text(props, "fill-color").and_then(parse_color),
+                frame: child_text(props, "frame-color").and_then(parse_color),
+                dither_pattern: child_text(props, "dither-pattern").map(str::to_string),
+                visible: child_text(props, "visible") != Some("false"),
+            };
+
+            layers.insert(key, layer);
+        }
+
+        Ok(LayerProperties(layers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fill_pattern::Diagonal;
+
+    const LYP: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<layer-properties>
+ <properties>
+  <frame-color>#ff0000</frame-color>
+  <fill-color>#00ff00</fill-color>
+  <dither-pattern>I9</dither-pattern>
+  <visible>true</visible>
+  <name>met1</name>
+  <source>68/20@1</source>
+ </properties>
+ <properties>
+  <fill-color>#0000ff</fill-color>
+  <visible>false</visible>
+  <source>via1 (68/44)</source>
+ </properties>
+ <properties>
+  <name>poly</name>
+  <group-members>
+   <frame-color/>
+   <fill-color>#ffffff</fill-color>
+   <source>66/20</source>
+  </group-members>
+  <group-members>
+   <source>66/5</source>
+  </group-members>
+ </properties>
+ <properties>
+  <source>*/*@*</source>
+ </properties>
+</layer-properties>
+"#;
+
+    #[test]
+    fn parses_layer_properties() {
+        let props = LayerProperties::parse(LYP).unwrap();
+
+        assert_eq!(
+            props.get(&LayerId::new(68, 20)),
+            Some(&LypLayer {
+                fill: Some(Color::rgb_u8(0, 255, 0)),
+                frame: Some(Color::rgb_u8(255, 0, 0)),
+                dither_pattern: Some("I9".to_string()),
+                visible: true,
+            })
+        );
+
+        assert_eq!(
+            props.get(&LayerId::new(68, 44)),
+            Some(&LypLayer {
+                fill: Some(Color::rgb_u8(0, 0, 255)),
+                frame: None,
+                dither_pattern: None,
+                visible: false,
+            })
+        );
+
+        // members of a group
+        assert_eq!(
+            props.get(&LayerId::new(66, 20)),
+            Some(&LypLayer {
+                fill: Some(Color::rgb_u8(255, 255, 255)),
+                frame: None,
+                dither_pattern: None,
+                visible: true,
+            })
+        );
+        assert_eq!(
+            props.get(&LayerId::new(66, 5)),
+            Some(&LypLayer {
+                fill: None,
+                frame: None,
+                dither_pattern: None,
+                visible: true,
+            })
+        );
+
+        // the group header has no source and the wildcard source is skipped
+        assert_eq!(props.len(), 4);
+    }
+
+    #[test]
+    fn missing_colors_fall_back() {
+        let props = LayerProperties::parse(LYP).unwrap();
+
+        let style = props
+            .get(&LayerId::new(68, 44))
+            .unwrap()
+            .style(|| Color::BLACK);
+        assert_eq!(style.fill, Color::rgb_u8(0, 0, 255));
+        assert_eq!(style.frame, Color::rgb_u8(0, 0, 255));
+
+        let style = props
+            .get(&LayerId::new(66, 5))
+            .unwrap()
+            .style(|| Color::BLACK);
+        assert_eq!(style.fill, Color::BLACK);
+        assert_eq!(style.frame, Color::BLACK);
+    }
+
+    #[test]
+    fn dither_pattern_sets_fill_pattern() {
+        let props = LayerProperties::parse(LYP).unwrap();
+
+        let style = |num, datatype| {
+            props
+                .get(&LayerId::new(num, datatype))
+                .unwrap()
+                .style(|| Color::BLACK)
+        };
+
+        assert_eq!(
+            style(68, 20).fill_pattern,
+            FillPattern::Hatch(Diagonal::Rising)
+        );
+        assert_eq!(style(68, 44).fill_pattern, FillPattern::Solid);
+    }
+}
@@ -1,5 +1,9 @@
+use std::{collections::BTreeSet, marker::PhantomData, time::Duration};
+
 use bevy::{
+    app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
     core_pipeline::clear_color::ClearColorConfig,
+    ecs::system::SystemParam,
     prelude::*,
     render::{
         camera::{CameraProjection, RenderTarget, Viewport, WindowOrigin},
@@ -10,84 +14,184 @@ use bevy::{
     },
     sprite::Anchor,
     tasks::{AsyncComputeTaskPool, Task},
+    window::WindowSettings,
+    winit::WinitPlugin,
 };
 
 use bevy_pancam::{PanCam, PanCamPlugin};
+use clap::Parser;
 
 use futures_lite::future;
 use geo::Intersects;
-use itertools::Itertools;
-use layout21::raw::{self, proto::ProtoImporter, BoundBox, BoundBoxTrait, Library};
-
+use layout21::raw::{self, BoundBox, BoundBoxTrait, Library};
+
+pub mod layer_visibility;
+pub mod live_view;
+pub mod readback;
+pub mod rect_instancing;
+pub mod tessellation_cache;
+pub mod tile_pyramid;
 pub mod tiled_renderer;
 
+use layer_visibility::LayerVisibilityPlugin;
+use live_view::LiveViewPlugin;
+use readback::AccumulationReadbackPlugin;
+use tile_pyramid::TilePyramidPlugin;
 use tiled_renderer::TiledRendererPlugin;
 
 use crate::{
+    cli::CliArgs,
+    import::{import_library, ErrorDetail, LibraryOpenError},
+    lyp::LayerProperties,
     types::{
-        AccumulationCam, AccumulationHandle, GeoRect, Tile, ACCUMULATION_CAMERA_PRIORITY,
-        DOWNSCALING_PASS_LAYER, NUM_TILES, TILE_SIZE_IN_PX,
+        AccumulationCam, AccumulationHandle, AccumulationOutline, AccumulationSprite, GeoRect,
+        RenderSlot, Tile, TileGrid, TiledRendererSettings, ACCUMULATION_CAMERA_PRIORITY,
     },
-    utils::{get_grid_shape, tilemap_stats_and_debug},
+    utils::tilemap_stats_and_debug,
 };
 
+mod cli;
+mod cpu_raster;
+mod fill_pattern;
+mod import;
+mod lyp;
 mod path_to_poly;
 mod types;
 mod utils;
 
-use path_to_poly::make_path_into_polygon;
+use path_to_poly::{make_path_into_polygon, PathEnds, PathError};
 
 use types::{
-    DrawTileEvent, FlattenedElems, GeoPolygon, GeoShapeEnum, HiResCam, HiResHandle, LayerColors,
-    Layers, LibLayers, LibraryWrapper, MainCamera, OpenVlsirLibCompleteEvent,
-    RenderingCompleteEvent, TileIndexIter, Tilemap, TilemapLowerLeft, VlsirLib, MAIN_CAMERA_LAYER,
-    MAIN_CAMERA_PRIORITY, TEXTURE_DIM,
+    DrawTile, DrawTileEvent, FlattenedElems, FlattenedLabels, GeoPolygon, GeoShapeEnum, HiResCam,
+    HiResHandles, HiddenLayers, LabelFont, LayerColors, LayerId, LayerStyle, Layers, LibLayers,
+    LibraryOpenFailedEvent, LibraryWrapper, LiveView, LoadedLayout, MainCamera, MainView,
+    OpenVlsirLibCompleteEvent, RenderingCompleteEvent, SendTilesLabel, TileIndexIter, TileKey,
+    TilePyramid, TileWalkCompleteEvent, Tilemap, ViewRect, VlsirLib, WorldToRender, LAYER_Z_RANGE,
+    MAIN_CAMERA_LAYER, MAIN_CAMERA_PRIORITY,
 };
 
+/// Print `e` and its sources and exit, for errors that happen before bevy's logger is set up
+fn exit_with_error(e: &dyn std::error::Error) -> ! {
+    eprintln!("error: {e}");
+    let mut source = e.source();
+    while let Some(e) = source {
+        eprintln!("  caused by: {e}");
+        source = e.source();
+    }
+    std::process::exit(1);
+}
+
 fn main() {
-    App::new()
-        .insert_resource(WindowDescriptor {
+    let args = CliArgs::parse();
+
+    if args.cpu {
+        // no bevy app, and so no logger, when rendering on the CPU
+        if let Err(e) = cpu_raster::render_overview_png(&args) {
+            exit_with_error(e.as_ref());
+        }
+        return;
+    }
+
+    let layer_properties = match &args.lyp {
+        Some(path) => LayerProperties::load(path).unwrap_or_else(|e| exit_with_error(&e)),
+        None => LayerProperties::default(),
+    };
+
+    let mut app = App::new();
+
+    if args.headless {
+        // render into the offscreen textures only, without a window or event loop
+        app.insert_resource(WindowSettings {
+            add_primary_window: false,
+            exit_on_all_closed: false,
+            close_when_requested: false,
+        })
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / 60.0,
+        )))
+        .add_plugins_with(DefaultPlugins, |group| group.disable::<WinitPlugin>())
+        .add_plugin(ScheduleRunnerPlugin);
+    } else {
+        app.insert_resource(WindowDescriptor {
             width: 1920.0,
             height: 1080.0,
             present_mode: bevy::window::PresentMode::Immediate,
             ..default()
         })
         .add_plugins(DefaultPlugins)
-        .add_plugin(PanCamPlugin)
+        .add_plugin(PanCamPlugin);
+    }
+
+    let settings = args.renderer_settings();
+
+    // the render plugin has set up the device, and the hi-res textures are created at startup,
+    // so settings it can't render are rejected before the app runs
+    let max_texture_dim = app
+        .world
+        .resource::<RenderDevice>()
+        .limits()
+        .max_texture_dimension_2d;
+    if settings.texture_dim() > max_texture_dim {
+        error!(
+            "{} tiles of {} px along the longer side of the layout need {} px hi-res textures, \
+             but this device supports at most {max_texture_dim} px",
+            settings.num_tiles,
+            settings.tile_size_in_px,
+            settings.texture_dim(),
+        );
+        std::process::exit(1);
+    }
+
+    app.insert_resource(settings)
+        .insert_resource(args)
+        .insert_resource(layer_properties)
         .add_plugin(TiledRendererPlugin)
+        .add_plugin(AccumulationReadbackPlugin)
+        .add_plugin(LayerVisibilityPlugin)
+        .add_plugin(TilePyramidPlugin)
+        .add_plugin(LiveViewPlugin)
         .init_resource::<LayerColors>()
         .init_resource::<OpenVlsirLibCompleteEvent>()
         .init_resource::<FlattenedElems>()
+        .init_resource::<FlattenedLabels>()
         .init_resource::<Tilemap>()
-        .init_resource::<TilemapLowerLeft>()
+        .init_resource::<WorldToRender>()
+        .init_resource::<TileGrid>()
         .init_resource::<Layers>()
         .init_resource::<LibLayers>()
         .init_resource::<VlsirLib>()
         .init_resource::<TileIndexIter>()
+        .init_resource::<MainView>()
         .add_event::<OpenVlsirLibCompleteEvent>()
+        .add_event::<LibraryOpenFailedEvent>()
         .add_event::<DrawTileEvent>()
         .add_event::<TileIndexIter>()
         .add_event::<RenderingCompleteEvent>()
+        .add_event::<TileWalkCompleteEvent>()
         .insert_resource(Msaa { samples: 1 })
         .add_startup_system(setup)
         .add_system(spawn_vlsir_open_task_sytem)
         .add_system(handle_vlsir_open_task_system)
+        .add_system(report_library_open_failure_system)
         .add_system(load_lib_system)
-        .add_system(iter_tile_index_system)
+        .add_system(resize_accumulation_sprites_system)
+        .add_system(iter_tile_index_system.label(SendTilesLabel))
         .add_system(camera_changed_system)
         .run();
 }
 
-fn initialize_hi_res_resources(commands: &mut Commands, images: &mut Assets<Image>) {
+/// A transparent `size` x `size` texture that cameras render into and sprites and materials
+/// sample
+pub fn render_target_image(size: u32, label: &'static str) -> Image {
     let size = Extent3d {
-        width: TEXTURE_DIM,
-        height: TEXTURE_DIM,
+        width: size,
+        height: size,
         ..default()
     };
 
     let mut image = Image {
         texture_descriptor: TextureDescriptor {
-            label: Some("HIRES_TEXTURE"),
+            label: Some(label),
             size,
             dimension: TextureDimension::D2,
             format: TextureFormat::Bgra8UnormSrgb,
@@ -100,61 +204,66 @@ fn initialize_hi_res_resources(commands: &mut Commands, images: &mut Assets<Imag
         ..default()
     };
 
-    // This fills the image with zeros
+    // fill image.data with zeroes
     image.resize(size);
 
-    let handle = images.add(image);
+    image
+}
 
-    let mut hires_cam = Camera2dBundle {
-        camera_2d: Camera2d::default(),
-        camera: Camera {
-            target: RenderTarget::Image(handle.clone()),
-            ..default()
-        },
-        ..default()
-    };
+fn initialize_hi_res_resources(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    settings: &TiledRendererSettings,
+) {
+    let image = render_target_image(settings.texture_dim(), "HIRES_TEXTURE");
+    let size = image.texture_descriptor.size;
+
+    // a texture and camera per render slot, each camera only sees the shapes of its slot
+    let handles = (0..settings.batch_size)
+        .map(|slot| {
+            let handle = images.add(image.clone());
+
+            let mut hires_cam = Camera2dBundle {
+                camera_2d: Camera2d::default(),
+                camera: Camera {
+                    target: RenderTarget::Image(handle.clone()),
+                    ..default()
+                },
+                ..default()
+            };
 
-    hires_cam.projection.window_origin = WindowOrigin::BottomLeft;
-    hires_cam
-        .projection
-        .update(size.width as f32, size.height as f32);
+            hires_cam.projection.window_origin = WindowOrigin::BottomLeft;
+            hires_cam
+                .projection
+                .update(size.width as f32, size.height as f32);
 
-    commands.spawn_bundle(hires_cam).insert(HiResCam);
-    commands.insert_resource(HiResHandle(handle));
-}
+            let slot = RenderSlot(slot);
 
-fn initialize_accumulation_resources(commands: &mut Commands, images: &mut Assets<Image>) {
-    let size = Extent3d {
-        width: TEXTURE_DIM,
-        height: TEXTURE_DIM,
-        ..default()
-    };
+            commands
+                .spawn_bundle(hires_cam)
+                .insert(slot.shape_layer())
+                .insert(slot)
+                .insert(HiResCam);
 
-    info!("creating new accumulation texture");
-    info!("accumulation texture size {size:?}");
-
-    // info!("CREATING NEW ACCUMULATION TEXTURE... SLEEPING FOR 5s");
+            handle
+        })
+        .collect();
 
-    // std::thread::sleep(Duration::from_secs(5));
+    commands.insert_resource(HiResHandles(handles));
+}
 
-    // This is the texture that will be rendered to.
-    let mut image = Image {
-        texture_descriptor: TextureDescriptor {
-            label: Some("ACCUMULATION_TEXTURE"),
-            size,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::Bgra8UnormSrgb,
-            mip_level_count: 1,
-            sample_count: 1,
-            usage: TextureUsages::TEXTURE_BINDING
-                | TextureUsages::COPY_DST
-                | TextureUsages::RENDER_ATTACHMENT,
-        },
-        ..default()
-    };
+fn initialize_accumulation_resources(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    settings: &TiledRendererSettings,
+) {
+    let mut image = render_target_image(settings.texture_dim(), "ACCUMULATION_TEXTURE");
+    // read back for --png
+    image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+    let size = image.texture_descriptor.size;
 
-    // fill image.data with zeroes
-    image.resize(size);
+    info!("creating new accumulation texture");
+    info!("accumulation texture size {size:?}");
 
     let handle = images.add(image);
 
@@ -170,7 +279,8 @@ fn initialize_accumulation_resources(commands: &mut Commands, images: &mut Asset
             transform: Transform::from_translation((0.0, 0.0, 1.0).into()),
             ..default()
         })
-        .insert(MAIN_CAMERA_LAYER);
+        .insert(MAIN_CAMERA_LAYER)
+        .insert(AccumulationSprite);
 
     // sprite that is 10% larger than the accumulation texture and underneath/futher from the camera
     // than the accumulation texture sprite to indicate where the texture is/outline it
@@ -187,31 +297,59 @@ fn initialize_accumulation_resources(commands: &mut Commands, images: &mut Asset
             ),
             ..default()
         })
-        .insert(MAIN_CAMERA_LAYER);
-
-    commands
-        .spawn_bundle(Camera2dBundle {
-            camera: Camera {
-                priority: ACCUMULATION_CAMERA_PRIORITY,
-                target: RenderTarget::Image(handle.clone()),
-                viewport: Some(Viewport {
-                    physical_size: UVec2::new(TILE_SIZE_IN_PX, TILE_SIZE_IN_PX),
+        .insert(MAIN_CAMERA_LAYER)
+        .insert(AccumulationOutline);
+
+    // a camera per render slot, writing the downscaled tile of its slot into its viewport
+    for slot in (0..settings.batch_size).map(RenderSlot) {
+        commands
+            .spawn_bundle(Camera2dBundle {
+                camera: Camera {
+                    priority: ACCUMULATION_CAMERA_PRIORITY,
+                    target: RenderTarget::Image(handle.clone()),
+                    viewport: Some(Viewport {
+                        physical_size: UVec2::new(
+                            settings.tile_size_in_px,
+                            settings.tile_size_in_px,
+                        ),
+                        ..default()
+                    }),
                     ..default()
-                }),
+                },
+                camera_2d: Camera2d {
+                    clear_color: ClearColorConfig::None,
+                },
                 ..default()
-            },
-            camera_2d: Camera2d {
-                clear_color: ClearColorConfig::None,
-            },
-            ..default()
-        })
-        .insert(DOWNSCALING_PASS_LAYER)
-        .insert(AccumulationCam);
+            })
+            .insert(slot.downscaling_layer())
+            .insert(slot)
+            .insert(AccumulationCam);
+    }
 
     commands.insert_resource(AccumulationHandle(handle));
 }
 
-fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    asset_server: Res<AssetServer>,
+    args: Res<CliArgs>,
+    settings: Res<TiledRendererSettings>,
+) {
+    initialize_hi_res_resources(&mut commands, &mut images, &settings);
+
+    commands.insert_resource(LabelFont(
+        args.label_font
+            .as_ref()
+            .map(|path| asset_server.load(path.as_path())),
+    ));
+
+    initialize_accumulation_resources(&mut commands, &mut images, &settings);
+
+    if args.headless {
+        return;
+    }
+
     // configure and spawn the main camera
     let mut camera = Camera2dBundle {
         camera: Camera {
@@ -229,12 +367,9 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
         .insert(MAIN_CAMERA_LAYER)
         .insert(MainCamera)
         .insert(PanCam::default());
-
-    initialize_hi_res_resources(&mut commands, &mut images);
-
-    initialize_accumulation_resources(&mut commands, &mut images);
 }
 
+/// Keep `MainView` up to date and reorder the rest of the tile walk around what is in view
 fn camera_changed_system(
     camera_q: Query<
         (&Transform, &OrthographicProjection),
@@ -243,23 +378,37 @@ fn camera_changed_system(
             With<MainCamera>,
         ),
     >,
+    settings: Res<TiledRendererSettings>,
+    mut main_view: ResMut<MainView>,
+    mut tile_index_iter: ResMut<TileIndexIter>,
 ) {
     for (t, proj) in camera_q.iter() {
         info!("Camera new transform {t:?}, scale {}", proj.scale);
+
+        **main_view = Some(ViewRect::from_camera(t, proj));
+
+        if let Some(remaining) = tile_index_iter.take() {
+            let mut remaining = remaining.collect::<Vec<(u32, u32)>>();
+            main_view.order_tiles(&mut remaining, settings.tile_size_in_px);
+            **tile_index_iter = Some(remaining.into_iter());
+        }
     }
 }
 
-fn spawn_vlsir_open_task_sytem(mut commands: Commands, mut already_done: Local<bool>) {
+fn spawn_vlsir_open_task_sytem(
+    mut commands: Commands,
+    args: Res<CliArgs>,
+    mut already_done: Local<bool>,
+) {
     if !*already_done {
         let thread_pool = AsyncComputeTaskPool::get();
 
-        let task: Task<Library> = thread_pool.spawn(async move {
-            let plib = raw::proto::proto::open(
-                "/home/colepoirier/Dropbox/rust_2020_onwards/doug/doug/libs/oscibear.proto",
-            )
-            .unwrap();
-            ProtoImporter::import(&plib, None).unwrap()
-        });
+        let path = args.input.clone();
+
+        info!("opening library {path:?}");
+
+        let task: Task<Result<Library, LibraryOpenError>> =
+            thread_pool.spawn(async move { import_library(&path) });
         let task = LibraryWrapper(task);
         commands.spawn().insert(task);
         *already_done = true;
@@ -271,226 +420,591 @@ fn handle_vlsir_open_task_system(
     mut lib: ResMut<VlsirLib>,
     mut vlsir_open_task_q: Query<(Entity, &mut LibraryWrapper)>,
     mut vlsir_open_lib_complete_event_writer: EventWriter<OpenVlsirLibCompleteEvent>,
+    mut library_open_failed_event_writer: EventWriter<LibraryOpenFailedEvent>,
 ) {
     for (entity, mut task) in vlsir_open_task_q.iter_mut() {
-        if let Some(vlsir_lib) = future::block_on(future::poll_once(&mut **task)) {
-            lib.lib = Some(vlsir_lib);
-            vlsir_open_lib_complete_event_writer.send(OpenVlsirLibCompleteEvent);
+        if let Some(result) = future::block_on(future::poll_once(&mut **task)) {
+            match result {
+                Ok(vlsir_lib) => {
+                    lib.lib = Some(vlsir_lib);
+                    vlsir_open_lib_complete_event_writer.send(OpenVlsirLibCompleteEvent);
+                }
+                Err(e) => {
+                    library_open_failed_event_writer.send(LibraryOpenFailedEvent(e.chain()));
+                }
+            }
             commands.entity(entity).despawn();
         }
     }
 }
 
+/// Report a library that couldn't be opened in the log and the window title. Without a window
+/// nothing else would ever happen, so a headless run exits with a failure status instead.
+fn report_library_open_failure_system(
+    args: Res<CliArgs>,
+    mut library_open_failed_event_reader: EventReader<LibraryOpenFailedEvent>,
+    mut windows: ResMut<Windows>,
+) {
+    for LibraryOpenFailedEvent(chain) in library_open_failed_event_reader.iter() {
+        error!("failed to open library:");
+        for (depth, msg) in chain.iter().enumerate() {
+            error!("{:indent$}{msg}", "", indent = 2 * depth);
+        }
+
+        if args.headless {
+            // AppExit would exit successfully
+            std::process::exit(1);
+        }
+
+        if let Some(window) = windows.get_primary_mut() {
+            window.set_title(format!("Failed to open library: {}", chain.join(": ")));
+        }
+    }
+}
+
+/// The resources `load_lib_system` replaces with those of a newly loaded layout
+#[derive(SystemParam)]
+struct LoadedLayoutResources<'w, 's> {
+    layers: ResMut<'w, Layers>,
+    hidden_layers: ResMut<'w, HiddenLayers>,
+    lib_layers: ResMut<'w, LibLayers>,
+    tilemap: ResMut<'w, Tilemap>,
+    grid: ResMut<'w, TileGrid>,
+    flattened_elems: ResMut<'w, FlattenedElems>,
+    flattened_labels: ResMut<'w, FlattenedLabels>,
+    world_to_render: ResMut<'w, WorldToRender>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
 fn load_lib_system(
     mut vlsir_open_lib_complete_event_reader: EventReader<OpenVlsirLibCompleteEvent>,
     vlsir_lib: Res<VlsirLib>,
+    args: Res<CliArgs>,
+    settings: Res<TiledRendererSettings>,
+    layer_properties: Res<LayerProperties>,
     mut layer_colors: ResMut<LayerColors>,
-    mut layers: ResMut<Layers>,
-    mut lib_layers: ResMut<LibLayers>,
-    mut tilemap: ResMut<Tilemap>,
+    mut loaded_res: LoadedLayoutResources,
+    mut library_open_failed_event_writer: EventWriter<LibraryOpenFailedEvent>,
     mut tile_index_iter: ResMut<TileIndexIter>,
-    mut flattened_elems_res: ResMut<FlattenedElems>,
-    mut min_offset_res: ResMut<TilemapLowerLeft>,
-    mut ev: EventWriter<DrawTileEvent>,
+    main_view: Res<MainView>,
+    accumulation_image: Res<AccumulationHandle>,
+    clear_color: Res<ClearColor>,
+    mut images: ResMut<Assets<Image>>,
 ) {
     for _ in vlsir_open_lib_complete_event_reader.iter() {
         let lib = vlsir_lib.lib.as_ref().unwrap();
 
+        let loaded = match load_layout(lib, &args, &settings, &layer_properties, &mut layer_colors)
         {
-            let lib_layers = &lib.layers.read().unwrap().slots;
-
-            for raw::Layer {
-                layernum, name: _, ..
-            } in lib_layers.values()
-            {
-                let num = *layernum as u8;
-                if let Some(_) = layers.insert(num, layer_colors.get_color()) {
-                    panic!(
-                        "Library layers corrupted multiple definitions for layer number {}",
-                        num
-                    );
-                }
+            Ok(loaded) => loaded,
+            Err(e) => {
+                library_open_failed_event_writer.send(LibraryOpenFailedEvent(e.chain()));
+                continue;
             }
-        }
+        };
 
-        *lib_layers = LibLayers(lib.layers.read().unwrap().clone());
+        *loaded_res.layers = loaded.layers;
+        *loaded_res.hidden_layers = loaded.hidden_layers;
+        *loaded_res.lib_layers = loaded.lib_layers;
+        *loaded_res.tilemap = loaded.tilemap;
+        *loaded_res.grid = loaded.grid;
+        *loaded_res.flattened_elems = loaded.flattened_elems;
+        *loaded_res.flattened_labels = loaded.flattened_labels;
+        *loaded_res.world_to_render = loaded.world_to_render;
+
+        let tilemap: &Tilemap = &loaded_res.tilemap;
+        let grid: &TileGrid = &loaded_res.grid;
+
+        let draw_labels = args.label_font.is_some();
+
+        tilemap_stats_and_debug(tilemap, draw_labels, args.heatmap_csv.as_deref());
+
+        // done here rather than in its own system so the texture is resized before the first
+        // tile is rendered into it
+        clear_accumulation_image(
+            images.get_mut(&accumulation_image).unwrap(),
+            grid,
+            &settings,
+            clear_color.0,
+        );
 
-        let cell_ptr = lib.cells.iter().last().unwrap();
+        // empty tiles are left as cleared above rather than going through the render walk
+        let mut non_empty_tiles = tilemap
+            .iter()
+            .filter(|(_, tile)| !tile.is_empty(draw_labels))
+            .map(|(key, _)| *key)
+            .collect::<Vec<(u32, u32)>>();
 
-        let cell = cell_ptr.read().unwrap();
+        main_view.order_tiles(&mut non_empty_tiles, settings.tile_size_in_px);
 
-        let flattened_elems = cell.layout.as_ref().unwrap().flatten().unwrap();
+        *tile_index_iter = TileIndexIter(Some(non_empty_tiles.into_iter()));
+    }
+}
+
+/// Size the accumulation texture to fit `grid` and fill it with `clear_color`, which is what
+/// every empty tile would look like after being rendered
+fn clear_accumulation_image(
+    image: &mut Image,
+    grid: &TileGrid,
+    settings: &TiledRendererSettings,
+    clear_color: Color,
+) {
+    let (width, height) = grid.texture_size(settings.tile_size_in_px);
 
-        info!("num elems including instances: {}", flattened_elems.len());
+    info!(
+        "resizing accumulation texture to {width}x{height} for a {}x{} tile grid",
+        grid.num_x, grid.num_y
+    );
 
-        let mut bbox = BoundBox::empty();
-        for elem in flattened_elems.iter() {
-            bbox = elem.inner.union(&bbox);
+    image.resize(Extent3d {
+        width,
+        height,
+        ..default()
+    });
+
+    // the accumulation texture is Bgra8UnormSrgb
+    let [r, g, b, a] = clear_color.as_rgba_f32().map(|c| (c * 255.0).round() as u8);
+    for px in image.data.chunks_exact_mut(4) {
+        px.copy_from_slice(&[b, g, r, a]);
+    }
+}
+
+/// Size the sprites showing the accumulation texture to fit the tile grid of the newly loaded
+/// library
+fn resize_accumulation_sprites_system(
+    grid: Res<TileGrid>,
+    settings: Res<TiledRendererSettings>,
+    mut sprite_q: Query<
+        (&mut Sprite, &mut Transform, Option<&AccumulationOutline>),
+        Or<(With<AccumulationSprite>, With<AccumulationOutline>)>,
+    >,
+) {
+    if !grid.is_changed() || grid.num_x == 0 || grid.num_y == 0 {
+        return;
+    }
+
+    let (width, height) = grid.texture_size(settings.tile_size_in_px);
+    let (width, height) = (width as f32, height as f32);
+
+    for (mut sprite, mut transform, outline) in sprite_q.iter_mut() {
+        if outline.is_some() {
+            sprite.custom_size = Some(Vec2::new(width * 1.1, height * 1.1));
+            transform.translation.x = -0.05 * width;
+            transform.translation.y = -0.05 * height;
+        } else {
+            sprite.custom_size = Some(Vec2::new(width, height));
         }
+    }
+}
 
-        assert!(!bbox.is_empty(), "bbox must be valid!");
-        *min_offset_res = TilemapLowerLeft {
-            x: bbox.p0.x as i64,
-            y: bbox.p0.y as i64,
-        };
+/// Flatten the selected top cell of `lib` and bin its shapes into a tilemap. This is shared by
+/// `load_lib_system` and the CPU renderer, which runs without bevy's app loop.
+pub fn load_layout(
+    lib: &Library,
+    args: &CliArgs,
+    settings: &TiledRendererSettings,
+    layer_properties: &LayerProperties,
+    layer_colors: &mut LayerColors,
+) -> Result<LoadedLayout, LibraryOpenError> {
+    let lib_layers = LibLayers(lib.layers.read().unwrap().clone());
+
+    let cell_ptr = match &args.top_cell {
+        Some(name) => lib
+            .cells
+            .iter()
+            .find(|c| c.read().unwrap().name == *name)
+            .ok_or_else(|| LibraryOpenError::NoSuchCell {
+                name: name.clone(),
+                available: lib
+                    .cells
+                    .iter()
+                    .map(|c| c.read().unwrap().name.clone())
+                    .collect(),
+            })?,
+        None => lib.cells.iter().last().ok_or(LibraryOpenError::NoCells)?,
+    };
+
+    let cell = cell_ptr.read().unwrap();
+
+    info!("rendering cell {}", cell.name);
+
+    let layout = cell
+        .layout
+        .as_ref()
+        .ok_or_else(|| LibraryOpenError::NoLayout {
+            cell: cell.name.clone(),
+        })?;
+
+    let mut flattened_elems = layout.flatten().map_err(|e| LibraryOpenError::Flatten {
+        cell: cell.name.clone(),
+        source: ErrorDetail(format!("{e:?}")),
+    })?;
+
+    info!("num elems including instances: {}", flattened_elems.len());
+
+    if !args.layers.is_empty() {
+        flattened_elems.retain(|el| {
+            lib_layers
+                .get(el.layer)
+                .map(|l| args.layer_enabled(l.layernum))
+                .unwrap_or(false)
+        });
+
+        info!(
+            "num elems on layers {:?}: {}",
+            args.layers,
+            flattened_elems.len()
+        );
+    }
+
+    let layer_ids = flattened_elems
+        .iter()
+        .map(|el| lib_layers.layer_id(el))
+        .collect::<BTreeSet<LayerId>>();
+
+    for id in layer_ids.iter().filter(|id| id.datatype < 0) {
+        warn!("layer {id} is a purpose without a datatype in the library");
+    }
+
+    let layers = build_layers(
+        &layer_ids,
+        &args.layer_stack,
+        layer_properties,
+        layer_colors,
+    );
+
+    let hidden_layers = HiddenLayers(
+        layers
+            .iter()
+            .filter(|(_, style)| !style.visible)
+            .map(|(id, _)| *id)
+            .collect(),
+    );
+
+    info!("num layers with shapes: {}", layers.len());
+
+    let mut bbox = BoundBox::empty();
+    for elem in flattened_elems.iter() {
+        bbox = elem.inner.union(&bbox);
+    }
+
+    if bbox.is_empty() {
+        return Err(LibraryOpenError::NoShapes {
+            cell: cell.name.clone(),
+        });
+    }
+
+    info!("flattened bbox is {bbox:?}");
+
+    let grid = TileGrid::from_bbox(&bbox, settings.num_tiles);
+
+    let world_to_render = WorldToRender::new(&grid, settings.texture_dim());
+
+    info!(
+        "dx: {}, dy: {}, tile_extent_in_worldspace: {}, grid: {}x{}",
+        bbox.p1.x - bbox.p0.x,
+        bbox.p1.y - bbox.p0.y,
+        grid.tile_size,
+        grid.num_x,
+        grid.num_y
+    );
+
+    let mut tilemap = grid.build_tilemap();
 
-        info!("flattened bbox is {bbox:?}");
+    let mut shape_count = 0;
 
-        let dx = (bbox.p1.x - bbox.p0.x) as u64;
-        let dy = (bbox.p1.y - bbox.p0.y) as u64;
+    let t = std::time::Instant::now();
 
-        let max_side_length = dx.max(dy);
-        // TODO: do this without converting to f64
-        let tile_size_in_world_space = (max_side_length as f64 / NUM_TILES as f64).ceil() as u64;
+    import_cell_shapes(
+        &grid,
+        &mut tilemap,
+        &flattened_elems,
+        settings.path_ends,
+        &mut shape_count,
+    );
 
-        let mut x = bbox.p0.x as i64;
-        let mut y = bbox.p0.y as i64;
+    info!("DONE {shape_count} shapes in {:?}!", t.elapsed());
 
-        // TODO: implement scalar multiplication for our Point (in types)
-        // TODO: Implement a From<raw::Point> impl for our Point
-        let tilemap_shift = raw::Point {
-            x: -x as isize,
-            y: -y as isize,
+    let mut flattened_labels = vec![];
+    flatten_labels(layout, &raw::Transform::identity(), &mut flattened_labels);
+
+    import_cell_labels(
+        &grid,
+        &mut tilemap,
+        &flattened_labels,
+        settings.label_height,
+    );
+
+    info!("num labels including instances: {}", flattened_labels.len());
+
+    if !flattened_labels.is_empty() && args.label_font.is_none() {
+        warn!("labels are only drawn with a --label-font");
+    }
+
+    Ok(LoadedLayout {
+        layers,
+        hidden_layers,
+        lib_layers,
+        tilemap,
+        grid,
+        flattened_elems: FlattenedElems(flattened_elems),
+        flattened_labels: FlattenedLabels(flattened_labels),
+        world_to_render,
+    })
+}
+
+/// Style every layer in `layer_ids` from `layer_properties`, or from the palette for layers it
+/// doesn't cover, and stack them: layers in `stack` are drawn bottom up in that order, above
+/// any other layers, which are drawn in `layer_ids` order
+pub fn build_layers(
+    layer_ids: &BTreeSet<LayerId>,
+    stack: &[LayerId],
+    layer_properties: &LayerProperties,
+    layer_colors: &mut LayerColors,
+) -> Layers {
+    let mut layers = Layers::default();
+
+    // the palette is only drawn from for layers the properties file doesn't cover, so their
+    // colours don't shift when it does
+    for id in layer_ids.iter() {
+        let style = match layer_properties.get(id) {
+            Some(props) => props.style(|| layer_colors.get_color()),
+            None => LayerStyle::from_color(layer_colors.get_color()),
         };
 
-        for iy in 0..NUM_TILES {
-            let ymin = y;
-            y += tile_size_in_world_space as i64;
-            let ymax = y;
-            for ix in 0..NUM_TILES {
-                let xmin = x;
-                x += tile_size_in_world_space as i64;
-                let xmax = x;
-
-                let extents = GeoRect::new((xmin, ymin), (xmax, ymax));
-
-                tilemap.insert(
-                    (ix, iy),
-                    Tile {
-                        extents,
-                        shapes: vec![],
-                    },
-                );
-            }
+        layers.insert(*id, style);
+    }
 
-            x = bbox.p0.x as i64;
-        }
+    let order = layer_ids
+        .iter()
+        .filter(|id| !stack.contains(id))
+        .chain(stack.iter().filter(|id| layer_ids.contains(id)));
 
-        let mut shape_count = 0;
+    let mut placed = BTreeSet::new();
+    let num_layers = layer_ids.len().max(1) as f32;
 
-        info!("{bbox:?}, dx: {dx}, dy: {dy}, tile_extent_in_worldspace: {}, num_tiles filled by short side of design: {}");
+    for (ix, id) in order.filter(|id| placed.insert(**id)).enumerate() {
+        layers.get_mut(id).unwrap().z = ix as f32 * LAYER_Z_RANGE / num_layers;
+    }
 
-        let t = std::time::Instant::now();
+    layers
+}
 
-        import_cell_shapes(
-            tilemap_shift,
-            max_side_length,
-            &mut tilemap,
-            &flattened_elems,
-            &mut shape_count,
-        );
+/// Collect the annotations of `layout` and of every cell instantiated below it, placed by
+/// `trans`. `Layout::flatten` only collects elements, so labels are flattened the same way here.
+fn flatten_labels(
+    layout: &raw::Layout,
+    trans: &raw::Transform,
+    labels: &mut Vec<raw::TextElement>,
+) {
+    for text in layout.annotations.iter() {
+        let mut label = text.clone();
+        label.loc = text.loc.transform(trans);
+        labels.push(label);
+    }
 
-        info!("DONE {shape_count} shapes in {:?}!", t.elapsed());
+    for inst in layout.insts.iter() {
+        let cell = inst.cell.read().unwrap();
 
-        *flattened_elems_res = FlattenedElems(flattened_elems);
+        if let Some(child) = &cell.layout {
+            let inst_trans =
+                raw::Transform::from_instance(&inst.loc, inst.reflect_vert, inst.angle);
+            flatten_labels(child, &raw::Transform::cascade(trans, &inst_trans), labels);
+        }
+    }
+}
 
-        tilemap_stats_and_debug(&tilemap);
+/// Width of a character of a label as a fraction of its height. Labels are binned before their
+/// font is loaded, so this errs on the wide side of most fonts.
+const LABEL_ADVANCE: f64 = 0.7;
 
-        let (x, y) = get_grid_shape(&tilemap);
+/// World space box the text of `label` takes when drawn `label_height` tall, above and to the
+/// right of its anchor point
+pub fn label_extents(label: &raw::TextElement, label_height: u32) -> GeoRect {
+    let (x, y) = (label.loc.x as i64, label.loc.y as i64);
+    let width = label.string.chars().count() as f64 * label_height as f64 * LABEL_ADVANCE;
 
-        let mut index_iter = (0..y).cartesian_product(0..x);
+    GeoRect::new((x, y), (x + width.ceil() as i64, y + label_height as i64))
+}
 
-        let (y, x) = index_iter.next().unwrap();
+/// Bin every label in `labels` into the tiles of `tilemap` that its text overlaps when drawn
+/// `label_height` tall, so that labels crossing tile edges aren't cut off. Labels entirely
+/// outside the grid are dropped.
+pub fn import_cell_labels(
+    grid: &TileGrid,
+    tilemap: &mut Tilemap,
+    labels: &[raw::TextElement],
+    label_height: u32,
+) {
+    for (idx, label) in labels.iter().enumerate() {
+        let extents = label_extents(label, label_height);
+        let (min, max) = (extents.min(), extents.max());
 
-        *tile_index_iter = TileIndexIter(Some(index_iter));
+        let (x_range, y_range) = grid.tile_range((min.x, min.y), (max.x, max.y));
 
-        ev.send(DrawTileEvent((x, y)));
+        for iy in y_range {
+            for ix in x_range.clone() {
+                if let Some(tile) = tilemap.get_mut(&(ix, iy)) {
+                    if extents.intersects(&tile.extents) {
+                        tile.labels.push(idx);
+                    }
+                }
+            }
+        }
     }
 }
 
+/// Send the next batch of tiles to render once the last one is done, one tile per render slot.
+/// Tiles of the live view go first, then those of the pyramid, as both are in view, then the rest
+/// of the walk over the tilemap.
 fn iter_tile_index_system(
     mut tile_index_iter: ResMut<TileIndexIter>,
+    mut pyramid: ResMut<TilePyramid>,
+    mut live_view: ResMut<LiveView>,
+    settings: Res<TiledRendererSettings>,
     mut draw_tile_ev: EventWriter<DrawTileEvent>,
     mut rendering_complete_ev: EventReader<RenderingCompleteEvent>,
+    mut tile_walk_complete_ev: EventWriter<TileWalkCompleteEvent>,
+    mut tiles_in_flight: Local<Vec<DrawTile>>,
+    mut tilemap_walked: Local<bool>,
 ) {
     for _ in rendering_complete_ev.iter() {
+        for tile in tiles_in_flight.drain(..) {
+            match tile {
+                DrawTile::Pyramid(key) => pyramid.finish(&key),
+                DrawTile::LiveView { render, .. } if render == live_view.render => {
+                    live_view.remaining = live_view.remaining.saturating_sub(1)
+                }
+                // tiles of a cancelled render may still finish
+                DrawTile::LiveView { .. } => {}
+            }
+        }
+    }
+
+    if !tiles_in_flight.is_empty() {
+        return;
+    }
+
+    while tiles_in_flight.len() < settings.batch_size {
+        let next = live_view
+            .pop_queued()
+            .or_else(|| pyramid.pop_queued().map(DrawTile::Pyramid))
+            .or_else(|| {
+                (**tile_index_iter)
+                    .as_mut()
+                    .and_then(|iter| iter.next())
+                    .map(|index| DrawTile::Pyramid(TileKey::new(0, index)))
+            });
+
+        match next {
+            Some(tile) => tiles_in_flight.push(tile),
+            None => break,
+        }
+    }
+
+    if tiles_in_flight.is_empty() {
         if tile_index_iter.is_some() {
-            if let Some((y, x)) = (**tile_index_iter).as_mut().unwrap().next() {
-                // if (x < 40) && (y == 170) {
-                let event = DrawTileEvent((x, y));
-                info!("Sending {event:?}");
-                draw_tile_ev.send(event);
-                // std::thread::sleep(std::time::Duration::from_millis(200));
-                // }
+            info!("all tiles rendered");
+            **tile_index_iter = None;
+
+            // walks re-rendering the tiles of toggled layers don't complete the tilemap again
+            if !*tilemap_walked {
+                *tilemap_walked = true;
+                tile_walk_complete_ev.send_default();
             }
         }
+        return;
+    }
+
+    for (slot, &tile) in tiles_in_flight.iter().enumerate() {
+        let event = DrawTileEvent {
+            tile,
+            slot: RenderSlot(slot),
+        };
+        info!("Sending {event:?}");
+        draw_tile_ev.send(event);
     }
 }
 
-pub fn import_cell_shapes(
-    tilemap_shift: raw::Point,
-    max_side_length: u32,
-    tilemap: &mut Tilemap,
-    elems: &Vec<raw::Element>,
-    shape_count: &mut u64,
-) {
-    for (idx, raw::Element { inner, .. }) in elems.iter().enumerate() {
-        let mut bbox = inner.bbox();
+/// The geometry `shape` is binned with, paths are converted to their outline with `path_ends`
+pub fn geo_shape(shape: &raw::Shape, path_ends: PathEnds) -> Result<GeoShapeEnum, PathError> {
+    match shape {
+        raw::Shape::Rect(r) => {
+            let raw::Rect { p0, p1 } = r;
 
-        if !bbox.is_empty() {
-            bbox.p0 = bbox.p0.shift(&tilemap_shift);
-            bbox.p1 = bbox.p1.shift(&tilemap_shift);
+            let xmin = p0.x as i64;
+            let ymin = p0.y as i64;
+            let xmax = p1.x as i64;
+            let ymax = p1.y as i64;
 
-            let BoundBox { p0, p1 } = bbox;
+            Ok(GeoShapeEnum::Rect(GeoRect::new((xmin, ymin), (xmax, ymax))))
+        }
+        raw::Shape::Polygon(p) => {
+            let poly = GeoPolygon::new(
+                p.points.iter().map(|p| (p.x as f64, p.y as f64)).collect(),
+                vec![],
+            );
 
-            let min_tile_x = p0.x as u32 / texture_dim;
-            let min_tile_y = p0.y as u32 / texture_dim;
-            let max_tile_x = p1.x as u32 / texture_dim;
-            let max_tile_y = p1.y as u32 / texture_dim;
+            Ok(GeoShapeEnum::Polygon(poly))
+        }
+        raw::Shape::Path(p) => make_path_into_polygon(p, path_ends).map(GeoShapeEnum::Polygon),
+    }
+}
 
-            let geo_shape = match inner {
-                raw::Shape::Rect(r) => {
-                    let raw::Rect { p0, p1 } = r;
+/// Bin every shape in `elems` into the tiles of `tilemap` that it intersects. Shapes are first
+/// narrowed down to tiles by their bounding box, clamped to `grid`, and then tested exactly.
+/// Paths are tested against their outline with `path_ends`.
+pub fn import_cell_shapes(
+    grid: &TileGrid,
+    tilemap: &mut Tilemap,
+    elems: &[raw::Element],
+    path_ends: PathEnds,
+    shape_count: &mut u64,
+) {
+    bin_shapes(grid, tilemap, elems, 0..elems.len(), path_ends, shape_count);
+}
 
-                    let xmin = p0.x as i64;
-                    let ymin = p0.y as i64;
-                    let xmax = p1.x as i64;
-                    let ymax = p1.y as i64;
+/// Bin the shapes of `elems` at `indices` into the tiles of `tilemap`, like `import_cell_shapes`
+pub fn bin_shapes(
+    grid: &TileGrid,
+    tilemap: &mut Tilemap,
+    elems: &[raw::Element],
+    indices: impl IntoIterator<Item = usize>,
+    path_ends: PathEnds,
+    shape_count: &mut u64,
+) {
+    for idx in indices {
+        let inner = &elems[idx].inner;
 
-                    let rect = GeoRect::new((xmin, ymin), (xmax, ymax));
+        let bbox = inner.bbox();
 
-                    let geo_shape = GeoShapeEnum::Rect(rect);
+        if !bbox.is_empty() {
+            let BoundBox { p0, p1 } = bbox;
 
-                    geo_shape
-                }
-                raw::Shape::Polygon(p) => {
-                    let poly = GeoPolygon::new(
-                        p.points.iter().map(|p| (p.x as i64, p.y as i64)).collect(),
-                        vec![],
-                    );
+            let (x_range, y_range) =
+                grid.tile_range((p0.x as i64, p0.y as i64), (p1.x as i64, p1.y as i64));
 
-                    GeoShapeEnum::Polygon(poly)
+            let geo_shape = match geo_shape(inner, path_ends) {
+                Ok(geo_shape) => geo_shape,
+                Err(e) => {
+                    warn!("skipping path {idx} that can't be converted to a polygon: {e}");
+                    *shape_count += 1;
+                    continue;
                 }
-                raw::Shape::Path(p) => GeoShapeEnum::Polygon(make_path_into_polygon(p)),
             };
 
-            for x in min_tile_x..=max_tile_x {
-                for y in min_tile_y..=max_tile_y {
-                    let Tile { extents, shapes } = tilemap.get_mut(&(x, y)).unwrap();
-
-                    let extents = &*extents;
-
-                    match &geo_shape {
-                        GeoShapeEnum::Rect(r) => {
-                            if r.intersects(extents) {
-                                shapes.push(idx);
-                            }
-                        }
-                        GeoShapeEnum::Polygon(p) => {
-                            if p.intersects(extents) {
-                                shapes.push(idx);
-                            }
-                        }
+            for x in x_range {
+                for y in y_range.clone() {
+                    let Tile {
+                        extents, shapes, ..
+                    } = match tilemap.get_mut(&(x, y)) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
+
+                    if geo_shape.intersects(extents) {
+                        shapes.push(idx);
                     }
                 }
             }
@@ -503,3 +1017,242 @@ pub fn import_cell_shapes(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::view::RenderLayers;
+    use types::{DOWNSCALING_PASS_LAYER, MAX_BATCH_SIZE};
+
+    /// 4x4 grid of 100x100 tiles with its origin at (-200, -200)
+    fn grid() -> TileGrid {
+        TileGrid {
+            origin: (-200, -200),
+            tile_size: 100,
+            num_x: 4,
+            num_y: 4,
+        }
+    }
+
+    fn rect_elem(p0: (isize, isize), p1: (isize, isize)) -> raw::Element {
+        raw::Element {
+            net: None,
+            layer: raw::LayerKey::default(),
+            purpose: raw::LayerPurpose::Drawing,
+            inner: raw::Shape::Rect(raw::Rect {
+                p0: raw::Point::new(p0.0, p0.1),
+                p1: raw::Point::new(p1.0, p1.1),
+            }),
+        }
+    }
+
+    /// Bin `elems` and return the sorted tile indices each of them ended up in
+    fn bin(elems: &[raw::Element]) -> Vec<Vec<(u32, u32)>> {
+        let grid = grid();
+        let mut tilemap = grid.build_tilemap();
+        let mut shape_count = 0;
+
+        import_cell_shapes(
+            &grid,
+            &mut tilemap,
+            elems,
+            PathEnds::Flush,
+            &mut shape_count,
+        );
+
+        assert_eq!(shape_count, elems.len() as u64);
+
+        (0..elems.len())
+            .map(|idx| {
+                let mut tiles = tilemap
+                    .iter()
+                    .filter(|(_, tile)| tile.shapes.contains(&idx))
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<(u32, u32)>>();
+                tiles.sort();
+                tiles
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tile_extents_follow_grid() {
+        let grid = grid();
+
+        assert_eq!(
+            grid.tile_extents(0, 0),
+            GeoRect::new((-200, -200), (-100, -100))
+        );
+        assert_eq!(grid.tile_extents(3, 1), GeoRect::new((100, -100), (200, 0)));
+        assert_eq!(grid.build_tilemap().len(), 16);
+    }
+
+    #[test]
+    fn from_bbox_covers_bbox() {
+        let bbox = raw::BoundBox {
+            p0: raw::Point::new(-10, 5),
+            p1: raw::Point::new(1000, 250),
+        };
+
+        let grid = TileGrid::from_bbox(&bbox, 8);
+
+        assert_eq!(grid.origin, (-10, 5));
+        assert_eq!((grid.num_x, grid.num_y), (8, 8));
+        assert!(grid.tile_size * 8 >= 1010);
+    }
+
+    #[test]
+    fn shape_inside_one_tile() {
+        assert_eq!(
+            bin(&[rect_elem((-190, -190), (-110, -110))]),
+            vec![vec![(0, 0)]]
+        );
+    }
+
+    #[test]
+    fn shape_straddling_tile_border() {
+        assert_eq!(
+            bin(&[rect_elem((-150, -150), (-50, -150 + 10))]),
+            vec![vec![(0, 0), (1, 0)]]
+        );
+        assert_eq!(
+            bin(&[rect_elem((-50, -50), (50, 50))]),
+            vec![vec![(1, 1), (1, 2), (2, 1), (2, 2)]]
+        );
+    }
+
+    #[test]
+    fn shape_on_bbox_edge_is_clamped() {
+        // the max corner of this shape lies exactly on the far edge of the grid, which
+        // would index one past the last tile
+        assert_eq!(
+            bin(&[rect_elem((150, 150), (200, 200))]),
+            vec![vec![(3, 3)]]
+        );
+    }
+
+    #[test]
+    fn shape_outside_grid_does_not_panic() {
+        assert_eq!(bin(&[rect_elem((500, 500), (600, 600))]), vec![vec![]]);
+        assert_eq!(
+            bin(&[rect_elem((-1000, -150), (-150, -140))]),
+            vec![vec![(0, 0)]]
+        );
+    }
+
+    #[test]
+    fn labels_are_binned_into_every_tile_their_text_overlaps() {
+        let grid = grid();
+        let mut tilemap = grid.build_tilemap();
+
+        let label = |string: &str, loc: (isize, isize)| raw::TextElement {
+            string: string.to_string(),
+            loc: raw::Point::new(loc.0, loc.1),
+        };
+        let labels = [
+            label("A", (-150, -150)),
+            // 140 wide at a height of 20, reaching into the next tile
+            label("ABCDEFGHIJ", (-150, -150)),
+            label("OUTSIDE", (500, 500)),
+        ];
+
+        import_cell_labels(&grid, &mut tilemap, &labels, 20);
+
+        let mut binned = vec![vec![]; labels.len()];
+        for (index, tile) in tilemap.iter() {
+            for idx in tile.labels.iter() {
+                binned[*idx].push(*index);
+            }
+        }
+        binned.iter_mut().for_each(|tiles| tiles.sort_unstable());
+
+        assert_eq!(binned, vec![vec![(0, 0)], vec![(0, 0), (1, 0)], vec![]]);
+    }
+
+    #[test]
+    fn tiles_in_view_are_rendered_first() {
+        let mut tiles = grid()
+            .build_tilemap()
+            .into_keys()
+            .collect::<Vec<(u32, u32)>>();
+
+        // without a view, row major from the bottom left
+        MainView::default().order_tiles(&mut tiles, 10);
+        assert_eq!(&tiles[..5], &[(0, 0), (1, 0), (2, 0), (3, 0), (0, 1)]);
+
+        // a view over tiles (2, 2) and (3, 2), centred on the latter
+        let view = MainView(Some(ViewRect {
+            min: Vec2::new(25.0, 22.0),
+            max: Vec2::new(45.0, 28.0),
+        }));
+        view.order_tiles(&mut tiles, 10);
+
+        assert_eq!(&tiles[..2], &[(3, 2), (2, 2)]);
+        // then the rest by distance, nearest first
+        assert_eq!(tiles[2], (3, 1));
+        assert_eq!(tiles.len(), 16);
+    }
+
+    #[test]
+    fn layers_follow_stack_and_properties() {
+        let ids = [(1, 0), (2, 0), (3, 0), (3, 5)]
+            .into_iter()
+            .map(|(num, datatype)| LayerId::new(num, datatype))
+            .collect::<BTreeSet<LayerId>>();
+
+        let stack =
+            [(3, 0), (1, 0), (9, 9), (3, 0)].map(|(num, datatype)| LayerId::new(num, datatype));
+
+        let props = LayerProperties::parse(
+            "<layer-properties><properties><fill-color>#000000</fill-color>\
+             <visible>false</visible><source>2/0@1</source></properties></layer-properties>",
+        )
+        .unwrap();
+
+        let layers = build_layers(&ids, &stack, &props, &mut LayerColors::default());
+
+        let layer = |num, datatype| layers.get(&LayerId::new(num, datatype)).unwrap();
+
+        // datatypes of the same layer number are distinct layers
+        assert_eq!(layers.len(), 4);
+
+        // unlisted layers in id order, then the stack bottom up, ignoring missing and repeated
+        // layers
+        assert_eq!(layer(2, 0).z, 0.0);
+        assert_eq!(layer(3, 5).z, LAYER_Z_RANGE / 4.0);
+        assert_eq!(layer(3, 0).z, LAYER_Z_RANGE / 2.0);
+        assert_eq!(layer(1, 0).z, 3.0 * LAYER_Z_RANGE / 4.0);
+
+        // from the properties file, without taking a palette colour
+        assert_eq!(layer(2, 0).fill, Color::BLACK);
+        assert!(!layer(2, 0).visible);
+
+        let mut palette = LayerColors::default();
+        assert_eq!(layer(1, 0).fill, palette.get_color());
+        assert_eq!(layer(3, 0).fill, palette.get_color());
+        assert_eq!(layer(3, 5).fill, palette.get_color());
+    }
+
+    #[test]
+    fn render_slots_draw_on_their_own_layers() {
+        let slots = (0..MAX_BATCH_SIZE)
+            .map(RenderSlot)
+            .collect::<Vec<RenderSlot>>();
+
+        // the single tile path keeps the layers it always had
+        assert_eq!(slots[0].shape_layer(), RenderLayers::default());
+        assert_eq!(slots[0].downscaling_layer(), DOWNSCALING_PASS_LAYER);
+
+        let mut layers = slots
+            .iter()
+            .flat_map(|slot| [slot.shape_layer(), slot.downscaling_layer()])
+            .collect::<Vec<RenderLayers>>();
+        layers.push(MAIN_CAMERA_LAYER);
+
+        for (i, a) in layers.iter().enumerate() {
+            for b in layers[i + 1..].iter() {
+                assert!(!a.intersects(b), "{a:?} and {b:?} overlap");
+            }
+        }
+    }
+}
@@ -1,7 +1,150 @@
+use std::{error::Error, f64::consts::PI, fmt, str::FromStr};
+
 use layout21::raw;
 
 use crate::types::GeoPolygon;
 
+/// Reasons a path cannot be converted into a polygon
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    /// Paths need at least two points to have a direction
+    TooFewPoints(usize),
+    /// Two consecutive points are the same, so the segment between them has no direction
+    DuplicatePoint(raw::Point),
+    /// The path turns back on itself at this point
+    Reversal(raw::Point),
+    /// A negative end extension pulls the end of the path at this point back past the other end
+    /// of its segment
+    ExtensionPastSegment(raw::Point),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::TooFewPoints(n) => {
+                write!(f, "expected a path with more than 1 point, found {n}")
+            }
+            PathError::DuplicatePoint(p) => {
+                write!(f, "path has consecutive duplicate points at {p:?}")
+            }
+            PathError::Reversal(p) => write!(f, "path reverses direction at {p:?}"),
+            PathError::ExtensionPastSegment(p) => {
+                write!(f, "path end at {p:?} is pulled back past its segment")
+            }
+        }
+    }
+}
+
+impl Error for PathError {}
+
+impl PathError {
+    /// The same error with any point it carries mapped back from doubled coordinates
+    fn undoubled(self) -> Self {
+        let halve = |p: raw::Point| raw::Point::new(p.x / 2, p.y / 2);
+
+        match self {
+            PathError::DuplicatePoint(p) => PathError::DuplicatePoint(halve(p)),
+            PathError::Reversal(p) => PathError::Reversal(halve(p)),
+            PathError::ExtensionPastSegment(p) => PathError::ExtensionPastSegment(halve(p)),
+            e => e,
+        }
+    }
+}
+
+/// How far the outline of a path reaches past its first and last points, mirroring the GDSII
+/// path types. `raw::Path` doesn't carry its path type, so one style applies to every path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathEnds {
+    /// The outline ends at the first and last points, GDSII path type 0
+    Flush,
+    /// The outline extends half the width past the first and last points, GDSII path type 2
+    Square,
+    /// The outline ends in a semicircle around the first and last points, GDSII path type 1
+    Round,
+    /// The outline extends `begin` past the first point and `end` past the last point, GDSII
+    /// path type 4
+    Custom { begin: isize, end: isize },
+}
+
+impl Default for PathEnds {
+    fn default() -> Self {
+        PathEnds::Flush
+    }
+}
+
+impl FromStr for PathEnds {
+    type Err = String;
+
+    /// Parses `flush`, `square`, `round` or `custom:<begin>,<end>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flush" => Ok(PathEnds::Flush),
+            "square" => Ok(PathEnds::Square),
+            "round" => Ok(PathEnds::Round),
+            _ => {
+                let extensions = s
+                    .strip_prefix("custom:")
+                    .and_then(|ext| ext.split_once(','))
+                    .and_then(|(begin, end)| {
+                        Some((
+                            begin.trim().parse::<isize>().ok()?,
+                            end.trim().parse::<isize>().ok()?,
+                        ))
+                    });
+
+                match extensions {
+                    Some((begin, end)) => Ok(PathEnds::Custom { begin, end }),
+                    None => Err(format!(
+                        "expected flush, square, round or custom:<begin>,<end>, found {s:?}"
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Exterior of a path outline, without the closing point, in doubled coordinates. Every
+/// coordinate and the width of a path are doubled before it is outlined, so that half of any
+/// width, odd or even, is a whole number of doubled units and rectilinear outlines stay exact.
+type DoubledOutline = Vec<(i64, i64)>;
+
+/// The two sides of a flush path outline in doubled coordinates, offset to the right (forward)
+/// and left (backward) of the path, each from its first point to its last. They can differ in
+/// length, a beveled turn adds two points on its outside and one on its inside.
+struct OutlineSides {
+    forward: DoubledOutline,
+    backward: DoubledOutline,
+}
+
+impl OutlineSides {
+    /// The outline running along the forward side and back along the backward side
+    fn join(self) -> DoubledOutline {
+        self.forward
+            .into_iter()
+            .chain(self.backward.into_iter().rev())
+            .collect()
+    }
+}
+
+/// A copy of `path` with every coordinate and its width doubled
+fn double_path(path: &raw::Path) -> raw::Path {
+    raw::Path {
+        points: path
+            .points
+            .iter()
+            .map(|p| raw::Point::new(p.x * 2, p.y * 2))
+            .collect(),
+        width: path.width * 2,
+    }
+}
+
+/// Number of segments approximating each semicircle of `PathEnds::Round`
+const ROUND_END_SEGMENTS: usize = 16;
+
+/// Longest miter of an angled path, as a multiple of half its width, the same as SVG's default
+/// `stroke-miterlimit`. Sharper turns are beveled instead.
+const MITER_LIMIT: f64 = 4.0;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum RectMove {
     Left,
@@ -10,25 +153,23 @@ enum RectMove {
     Down,
 }
 
-fn calculate_move(p0: raw::Point, p1: raw::Point) -> RectMove {
-    if p0.x == p1.x {
-        if p0.y == p1.y {
-            panic!("Cannot handle distinct points in a path with the same coordinate: p0 {p0:?} and p1 {p1:?}");
-        } else if p0.y < p1.y {
-            RectMove::Up
+fn calculate_move(p0: raw::Point, p1: raw::Point) -> Result<RectMove, PathError> {
+    if p0 == p1 {
+        Err(PathError::DuplicatePoint(p0))
+    } else if p0.x == p1.x {
+        if p0.y < p1.y {
+            Ok(RectMove::Up)
         } else {
-            RectMove::Down
+            Ok(RectMove::Down)
         }
     } else if p0.y == p1.y {
-        if p0.x == p1.x {
-            panic!("Cannot handle distinct points in a path with the same coordinate: p0 {p0:?} and p1 {p1:?}");
-        } else if p0.x < p1.x {
-            RectMove::Right
+        if p0.x < p1.x {
+            Ok(RectMove::Right)
         } else {
-            RectMove::Left
+            Ok(RectMove::Left)
         }
     } else {
-        panic!("rectilinear moves expected, but found: p0 {p0:?} and p1 {p1:?}");
+        unreachable!("non-rectilinear segments are handled by make_mitered_polygon")
     }
 }
 
@@ -146,23 +287,130 @@ fn shift_left_up(
     shift_right_down(backward_poly_points, forward_poly_points, p0, half_width);
 }
 
-pub fn make_path_into_polygon(path: &raw::Path) -> GeoPolygon {
+/// Outline a path with arbitrary angle segments, offsetting each point along the miter of the
+/// segments meeting there so that the offset edges stay parallel to the path at `half_width`.
+/// Turns sharp enough for the miter to be longer than `MITER_LIMIT` are beveled on their outside.
+fn make_mitered_polygon(path: &raw::Path, half_width: f64) -> Result<OutlineSides, PathError> {
     let num_points = path.points.len();
 
+    // unit direction and right hand normal of each segment
+    let mut normals = Vec::with_capacity(num_points - 1);
+    for w in path.points.windows(2) {
+        let (p0, p1) = (w[0], w[1]);
+        if p0 == p1 {
+            return Err(PathError::DuplicatePoint(p0));
+        }
+        let (dx, dy) = ((p1.x - p0.x) as f64, (p1.y - p0.y) as f64);
+        let len = (dx * dx + dy * dy).sqrt();
+        normals.push((dy / len, -dx / len));
+    }
+
     let mut forward_poly_points = Vec::with_capacity(num_points);
     let mut backward_poly_points = Vec::with_capacity(num_points);
-    assert_eq!(
-        path.width % 2,
-        0,
-        "width must be even for our code's assumptions to hold!"
-    );
-    let half_width = (path.width / 2) as isize; // assuming that widths are even!
-
-    assert!(
-        num_points > 1,
-        "Expected number of points in path to be > 1"
-    );
-    let start_move = calculate_move(path.points[0], path.points[1]);
+
+    let offset = |p: raw::Point, (ox, oy): (f64, f64), side: f64| {
+        (
+            p.x as f64 + side * ox * half_width,
+            p.y as f64 + side * oy * half_width,
+        )
+    };
+
+    forward_poly_points.push(offset(path.points[0], normals[0], 1.0));
+    backward_poly_points.push(offset(path.points[0], normals[0], -1.0));
+
+    for ix in 1..(num_points - 1) {
+        let p = path.points[ix];
+        let (n0, n1) = (normals[ix - 1], normals[ix]);
+        let cos = n0.0 * n1.0 + n0.1 * n1.1;
+
+        // the miter of a segment doubling back on itself is infinitely long
+        if 1.0 + cos < 1e-9 {
+            return Err(PathError::Reversal(p));
+        }
+
+        // bisector of the two normals, scaled so that its projection onto each normal is 1
+        let miter = ((n0.0 + n1.0) / (1.0 + cos), (n0.1 + n1.1) / (1.0 + cos));
+
+        if (2.0 / (1.0 + cos)).sqrt() <= MITER_LIMIT {
+            forward_poly_points.push(offset(p, miter, 1.0));
+            backward_poly_points.push(offset(p, miter, -1.0));
+            continue;
+        }
+
+        // the inside of the turn keeps its miter, the outside is cut across from the end of
+        // one segment's offset edge to the start of the next
+        let turns_left = n0.0 * n1.1 - n0.1 * n1.0 > 0.0;
+        let (outside, outside_side, inside, inside_side) = if turns_left {
+            (
+                &mut forward_poly_points,
+                1.0,
+                &mut backward_poly_points,
+                -1.0,
+            )
+        } else {
+            (
+                &mut backward_poly_points,
+                -1.0,
+                &mut forward_poly_points,
+                1.0,
+            )
+        };
+        outside.push(offset(p, n0, outside_side));
+        outside.push(offset(p, n1, outside_side));
+        inside.push(offset(p, miter, inside_side));
+    }
+
+    forward_poly_points.push(offset(
+        path.points[num_points - 1],
+        normals[num_points - 2],
+        1.0,
+    ));
+    backward_poly_points.push(offset(
+        path.points[num_points - 1],
+        normals[num_points - 2],
+        -1.0,
+    ));
+
+    let round = |points: Vec<(f64, f64)>| -> DoubledOutline {
+        points
+            .into_iter()
+            .map(|(x, y)| (x.round() as i64, y.round() as i64))
+            .collect()
+    };
+
+    Ok(OutlineSides {
+        forward: round(forward_poly_points),
+        backward: round(backward_poly_points),
+    })
+}
+
+/// Outline a path with flush ends. The outline is built by walking the path and offsetting each
+/// point by half the width to its right (forward) and left (backward), and the two sides are
+/// returned for the caller to join with the path's ends. `path` is in doubled coordinates, so rectilinear paths are offset exactly in integer
+/// coordinates, paths with any other angles are mitered in floating point and rounded to the
+/// doubled grid.
+fn make_flush_polygon(path: &raw::Path) -> Result<OutlineSides, PathError> {
+    let num_points = path.points.len();
+
+    if num_points < 2 {
+        return Err(PathError::TooFewPoints(num_points));
+    }
+
+    let mut forward_poly_points = Vec::with_capacity(num_points);
+    let mut backward_poly_points = Vec::with_capacity(num_points);
+
+    let rectilinear = path
+        .points
+        .windows(2)
+        .all(|w| w[0].x == w[1].x || w[0].y == w[1].y);
+
+    if !rectilinear {
+        return make_mitered_polygon(path, (path.width / 2) as f64);
+    }
+
+    let half_width = (path.width / 2) as isize;
+
+    let start_move = calculate_move(path.points[0], path.points[1])?;
 
     match start_move {
         RectMove::Right => shift_pure_right(
@@ -196,66 +444,64 @@ pub fn make_path_into_polygon(path: &raw::Path) -> GeoPolygon {
     for ix in 1..(num_points - 1) {
         let p0 = path.points[ix];
         let p1 = path.points[ix + 1];
-        let next_move = calculate_move(p0, p1);
+        let next_move = calculate_move(p0, p1)?;
         match (last_move, next_move) {
             (RectMove::Right, RectMove::Right) => shift_pure_right(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Left, RectMove::Left) => shift_pure_left(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Up, RectMove::Up) => shift_pure_up(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Down, RectMove::Down) => shift_pure_down(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Right, RectMove::Down) | (RectMove::Down, RectMove::Right) => {
                 shift_right_down(
                     &mut forward_poly_points,
                     &mut backward_poly_points,
-                    path.points[0],
+                    p0,
                     half_width,
                 )
             }
             (RectMove::Right, RectMove::Up) | (RectMove::Up, RectMove::Right) => shift_right_up(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Left, RectMove::Up) | (RectMove::Up, RectMove::Left) => shift_left_up(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
             (RectMove::Left, RectMove::Down) | (RectMove::Down, RectMove::Left) => shift_left_down(
                 &mut forward_poly_points,
                 &mut backward_poly_points,
-                path.points[0],
+                p0,
                 half_width,
             ),
-            (_, _) => panic!(
-                "Received opposing last/next moves! last: {last_move:?}, next: {next_move:?}"
-            ),
+            (_, _) => return Err(PathError::Reversal(p0)),
         }
         last_move = next_move;
     }
 
-    let end_move = calculate_move(path.points[num_points - 2], path.points[num_points - 1]);
+    let end_move = calculate_move(path.points[num_points - 2], path.points[num_points - 1])?;
     match end_move {
         RectMove::Right => shift_pure_right(
             &mut forward_poly_points,
@@ -283,12 +529,516 @@ pub fn make_path_into_polygon(path: &raw::Path) -> GeoPolygon {
         ),
     }
 
-    GeoPolygon::new(
-        forward_poly_points
+    let to_outline = |points: Vec<raw::Point>| -> DoubledOutline {
+        points
             .into_iter()
-            .chain(backward_poly_points.into_iter().rev())
             .map(|p| (p.x as i64, p.y as i64))
+            .collect()
+    };
+
+    Ok(OutlineSides {
+        forward: to_outline(forward_poly_points),
+        backward: to_outline(backward_poly_points),
+    })
+}
+
+/// Move `to` by `by` further along the direction from `from` to `to`. Rectilinear segments are
+/// extended exactly, others are rounded to the nearest integer point.
+fn extend_point(from: raw::Point, to: raw::Point, by: isize) -> raw::Point {
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+
+    if dx == 0 || dy == 0 {
+        raw::Point::new(to.x + dx.signum() * by, to.y + dy.signum() * by)
+    } else {
+        let len = ((dx * dx + dy * dy) as f64).sqrt();
+        let scale = by as f64 / len;
+        raw::Point::new(
+            to.x + (dx as f64 * scale).round() as isize,
+            to.y + (dy as f64 * scale).round() as isize,
+        )
+    }
+}
+
+/// Whether extending the end `to` of the segment from `from` by `by` keeps it on the same side
+/// of `from`
+fn extension_fits(from: raw::Point, to: raw::Point, by: isize) -> bool {
+    let (dx, dy) = ((to.x - from.x) as f64, (to.y - from.y) as f64);
+    (by as f64) > -(dx * dx + dy * dy).sqrt()
+}
+
+/// A copy of `path` with its first point moved back by `begin` and its last point moved on by
+/// `end`, along their segments. Negative extensions may shorten the end segments but not pull
+/// an end back past the other end of its segment, which on a single segment path is pulled back
+/// too.
+fn extend_path_ends(path: &raw::Path, begin: isize, end: isize) -> Result<raw::Path, PathError> {
+    let num_points = path.points.len();
+
+    if num_points < 2 {
+        return Err(PathError::TooFewPoints(num_points));
+    }
+
+    let (first, second) = (path.points[0], path.points[1]);
+    let (second_last, last) = (path.points[num_points - 2], path.points[num_points - 1]);
+
+    if first == second {
+        return Err(PathError::DuplicatePoint(first));
+    }
+    if second_last == last {
+        return Err(PathError::DuplicatePoint(last));
+    }
+    if !extension_fits(second, first, begin) {
+        return Err(PathError::ExtensionPastSegment(first));
+    }
+    if !extension_fits(second_last, last, end) {
+        return Err(PathError::ExtensionPastSegment(last));
+    }
+    // both ends of a single segment path move along the same segment, and mustn't cross
+    if num_points == 2 && !extension_fits(first, last, begin + end) {
+        return Err(PathError::ExtensionPastSegment(last));
+    }
+
+    let mut points = path.points.clone();
+    points[0] = extend_point(second, first, begin);
+    points[num_points - 1] = extend_point(second_last, last, end);
+
+    Ok(raw::Path {
+        points,
+        width: path.width,
+    })
+}
+
+/// Points of the arc of radius `radius` around `center`, going counter-clockwise for half a turn
+/// from `start`, excluding `start` and the opposite point
+fn semicircle(center: raw::Point, start: (i64, i64), radius: f64) -> Vec<(i64, i64)> {
+    let (cx, cy) = (center.x as f64, center.y as f64);
+    let start_angle = (start.1 as f64 - cy).atan2(start.0 as f64 - cx);
+
+    (1..ROUND_END_SEGMENTS)
+        .map(|i| {
+            let angle = start_angle + PI * i as f64 / ROUND_END_SEGMENTS as f64;
+            (
+                (cx + radius * angle.cos()).round() as i64,
+                (cy + radius * angle.sin()).round() as i64,
+            )
+        })
+        .collect()
+}
+
+/// Join the sides of a flush outline of `path` with semicircles instead of flat ends. The end
+/// cap runs from the end of the forward side to the end of the backward side, and the begin cap
+/// from the start of the backward side back to the start of the forward side.
+fn add_round_ends(sides: OutlineSides, path: &raw::Path) -> DoubledOutline {
+    let radius = path.width as f64 / 2.0;
+    let first = path.points[0];
+    let last = path.points[path.points.len() - 1];
+
+    let end_cap = semicircle(last, *sides.forward.last().unwrap(), radius);
+    let begin_cap = semicircle(first, sides.backward[0], radius);
+
+    sides
+        .forward
+        .into_iter()
+        .chain(end_cap)
+        .chain(sides.backward.into_iter().rev())
+        .chain(begin_cap)
+        .collect()
+}
+
+/// Convert a path into the polygon outlining it, with its ends shaped by `ends`. The outline is
+/// built in doubled coordinates so paths of any width are handled, and halved on the way out,
+/// leaving every vertex of a rectilinear path on the half database unit grid.
+pub fn make_path_into_polygon(path: &raw::Path, ends: PathEnds) -> Result<GeoPolygon, PathError> {
+    let doubled = double_path(path);
+
+    let outline = match ends {
+        PathEnds::Flush => make_flush_polygon(&doubled).map(OutlineSides::join),
+        PathEnds::Square => {
+            let half_width = path.width as isize;
+            extend_path_ends(&doubled, half_width, half_width)
+                .and_then(|extended| make_flush_polygon(&extended))
+                .map(OutlineSides::join)
+        }
+        PathEnds::Round => {
+            make_flush_polygon(&doubled).map(|sides| add_round_ends(sides, &doubled))
+        }
+        PathEnds::Custom { begin, end } => extend_path_ends(&doubled, begin * 2, end * 2)
+            .and_then(|extended| make_flush_polygon(&extended))
+            .map(OutlineSides::join),
+    }
+    .map_err(PathError::undoubled)?;
+
+    Ok(GeoPolygon::new(
+        outline
+            .into_iter()
+            .map(|(x, y)| (x as f64 / 2.0, y as f64 / 2.0))
             .collect(),
         vec![],
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Intersects;
+
+    use super::*;
+
+    fn path(points: &[(isize, isize)], width: usize) -> raw::Path {
+        raw::Path {
+            points: points.iter().map(|&(x, y)| raw::Point::new(x, y)).collect(),
+            width,
+        }
+    }
+
+    /// The exterior of `poly` without the closing point
+    fn outline(poly: &GeoPolygon) -> Vec<(f64, f64)> {
+        let mut points = poly
+            .exterior()
+            .points()
+            .map(|p| (p.x(), p.y()))
+            .collect::<Vec<(f64, f64)>>();
+        points.pop();
+        points
+    }
+
+    #[test]
+    fn manhattan_paths() {
+        #[rustfmt::skip]
+        let cases: &[(&str, &[(isize, isize)], &[(i64, i64)])] = &[
+            (
+                "straight",
+                &[(0, 0), (5, 0), (10, 0)],
+                &[(0, -1), (5, -1), (10, -1), (10, 1), (5, 1), (0, 1)],
+            ),
+            (
+                "L",
+                &[(0, 0), (10, 0), (10, 10)],
+                &[(0, -1), (11, -1), (11, 10), (9, 10), (9, 1), (0, 1)],
+            ),
+            (
+                "U",
+                &[(0, 10), (0, 0), (10, 0), (10, 10)],
+                &[(-1, 10), (-1, -1), (11, -1), (11, 10), (9, 10), (9, 1), (1, 1), (1, 10)],
+            ),
+            (
+                "Z",
+                &[(0, 10), (10, 10), (10, 0), (20, 0)],
+                &[(0, 9), (9, 9), (9, -1), (20, -1), (20, 1), (11, 1), (11, 11), (0, 11)],
+            ),
+            (
+                "staircase",
+                &[(0, 0), (10, 0), (10, 10), (20, 10), (20, 20)],
+                &[
+                    (0, -1), (11, -1), (11, 9), (21, 9), (21, 20),
+                    (19, 20), (19, 11), (9, 11), (9, 1), (0, 1),
+                ],
+            ),
+        ];
+
+        for (name, points, expected) in cases {
+            let poly = make_path_into_polygon(&path(points, 2), PathEnds::Flush)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            let expected = expected
+                .iter()
+                .map(|&(x, y)| (x as f64, y as f64))
+                .collect::<Vec<(f64, f64)>>();
+            assert_eq!(outline(&poly), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn angled_paths() {
+        #[rustfmt::skip]
+        let cases: &[(&str, &[(isize, isize)], &[(f64, f64)])] = &[
+            (
+                "45 degree segment",
+                &[(0, 0), (100, 100)],
+                &[(7.0, -7.0), (107.0, 93.0), (93.0, 107.0), (-7.0, 7.0)],
+            ),
+            (
+                "45 degree then horizontal",
+                &[(0, 0), (100, 100), (200, 100)],
+                &[(7.0, -7.0), (104.0, 90.0), (200.0, 90.0), (200.0, 110.0), (96.0, 110.0), (-7.0, 7.0)],
+            ),
+            (
+                "arbitrary angles",
+                &[(0, 0), (300, 400), (600, 0)],
+                &[(8.0, -6.0), (300.0, 383.5), (592.0, -6.0), (608.0, 6.0), (300.0, 416.5), (-8.0, 6.0)],
+            ),
+        ];
+
+        for (name, points, expected) in cases {
+            let poly = make_path_into_polygon(&path(points, 20), PathEnds::Flush)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert_eq!(outline(&poly), expected.to_vec(), "{name}");
+        }
+    }
+
+    #[test]
+    fn sharp_turns_are_beveled() {
+        // a 10 degree turn would otherwise miter out to over 11 times half the width
+        let points = [(0, 0), (1000, 0), (0, 176)];
+        let poly = make_path_into_polygon(&path(&points, 20), PathEnds::Flush).unwrap();
+        let outline = outline(&poly);
+
+        assert_eq!(outline.len(), 7);
+        assert!(
+            outline
+                .iter()
+                .all(|&(x, _)| x <= 1000.0 + MITER_LIMIT * 10.0),
+            "{outline:?}"
+        );
+        // the bevel ends each offset edge square to its segment
+        assert!(outline.contains(&(1000.0, -10.0)), "{outline:?}");
+    }
+
+    /// Whether any two edges of `outline` that don't follow each other cross or touch
+    fn self_intersects(outline: &[(f64, f64)]) -> bool {
+        let n = outline.len();
+        let edge = |i: usize| geo::Line::new(outline[i], outline[(i + 1) % n]);
+
+        (0..n).any(|i| {
+            (i + 2..n)
+                .filter(|&j| (j + 1) % n != i)
+                .any(|j| edge(i).intersects(&edge(j)))
+        })
+    }
+
+    #[test]
+    fn round_ends_around_beveled_turns() {
+        // sharp turns to the left and to the right, each beveled on the outside of the turn so
+        // that the sides of the outline differ in length
+        for points in [
+            [(0, 0), (1000, 0), (0, 176)],
+            [(0, 0), (1000, 0), (0, -176)],
+        ] {
+            let poly = make_path_into_polygon(&path(&points, 20), PathEnds::Round).unwrap();
+            let outline = outline(&poly);
+
+            assert!(!self_intersects(&outline), "{points:?}: {outline:?}");
+
+            // the points of each cap run from the end of one side to the end of the other
+            for (x, y) in [points[0], points[2]] {
+                let on_cap = outline
+                    .iter()
+                    .map(|&(px, py)| ((px - x as f64).hypot(py - y as f64) - 10.0).abs() <= 1.0)
+                    .collect::<Vec<bool>>();
+                let runs = (0..on_cap.len())
+                    .filter(|&i| on_cap[i] && !on_cap[(i + on_cap.len() - 1) % on_cap.len()])
+                    .count();
+
+                assert_eq!(
+                    on_cap.iter().filter(|&&on| on).count(),
+                    ROUND_END_SEGMENTS + 1,
+                    "{points:?}: {outline:?}"
+                );
+                assert_eq!(runs, 1, "{points:?}: {outline:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn odd_width_paths() {
+        #[rustfmt::skip]
+        let cases: &[(&str, &[(isize, isize)], usize, PathEnds, &[(f64, f64)])] = &[
+            (
+                "straight",
+                &[(0, 0), (10, 0)], 3, PathEnds::Flush,
+                &[(0.0, -1.5), (10.0, -1.5), (10.0, 1.5), (0.0, 1.5)],
+            ),
+            (
+                "L",
+                &[(0, 0), (10, 0), (10, 10)], 1, PathEnds::Flush,
+                &[(0.0, -0.5), (10.5, -0.5), (10.5, 10.0), (9.5, 10.0), (9.5, 0.5), (0.0, 0.5)],
+            ),
+            (
+                "square ends",
+                &[(0, 0), (10, 0)], 3, PathEnds::Square,
+                &[(-1.5, -1.5), (11.5, -1.5), (11.5, 1.5), (-1.5, 1.5)],
+            ),
+            (
+                "45 degree segment",
+                &[(0, 0), (100, 100)], 5, PathEnds::Flush,
+                &[(2.0, -2.0), (102.0, 98.0), (98.0, 102.0), (-2.0, 2.0)],
+            ),
+        ];
+
+        for (name, points, width, ends, expected) in cases {
+            let poly = make_path_into_polygon(&path(points, *width), *ends)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert_eq!(outline(&poly), expected.to_vec(), "{name}");
+        }
+    }
+
+    /// Area enclosed by the exterior of `poly`
+    fn area(poly: &GeoPolygon) -> f64 {
+        let points = outline(poly);
+        let twice_area: f64 = (0..points.len())
+            .map(|i| {
+                let (x0, y0) = points[i];
+                let (x1, y1) = points[(i + 1) % points.len()];
+                x0 * y1 - x1 * y0
+            })
+            .sum();
+        twice_area.abs() / 2.0
+    }
+
+    #[test]
+    fn path_ends() {
+        let round_ends = std::f64::consts::PI * 10.0 * 10.0;
+
+        let cases: &[(&str, &[(isize, isize)], PathEnds, f64)] = &[
+            ("flush", &[(0, 0), (100, 0)], PathEnds::Flush, 2000.0),
+            ("square", &[(0, 0), (100, 0)], PathEnds::Square, 2400.0),
+            (
+                "round",
+                &[(0, 0), (100, 0)],
+                PathEnds::Round,
+                2000.0 + round_ends,
+            ),
+            (
+                "custom",
+                &[(0, 0), (100, 0)],
+                PathEnds::Custom { begin: 5, end: 25 },
+                2600.0,
+            ),
+            (
+                "flush L",
+                &[(0, 0), (100, 0), (100, 100)],
+                PathEnds::Flush,
+                4000.0,
+            ),
+            (
+                "square L",
+                &[(0, 0), (100, 0), (100, 100)],
+                PathEnds::Square,
+                4400.0,
+            ),
+            (
+                "round L",
+                &[(0, 0), (100, 0), (100, 100)],
+                PathEnds::Round,
+                4000.0 + round_ends,
+            ),
+            (
+                "square diagonal",
+                &[(0, 0), (60, 80)],
+                PathEnds::Square,
+                2400.0,
+            ),
+        ];
+
+        for (name, points, ends, expected) in cases {
+            let poly = make_path_into_polygon(&path(points, 20), *ends)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            let area = area(&poly);
+            assert!(
+                (area - expected).abs() < 0.01 * expected,
+                "{name}: expected area {expected}, found {area}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_path_ends() {
+        assert_eq!("flush".parse(), Ok(PathEnds::Flush));
+        assert_eq!("square".parse(), Ok(PathEnds::Square));
+        assert_eq!("round".parse(), Ok(PathEnds::Round));
+        assert_eq!(
+            "custom:5,-3".parse(),
+            Ok(PathEnds::Custom { begin: 5, end: -3 })
+        );
+        assert!("custom:5".parse::<PathEnds>().is_err());
+        assert!("mitered".parse::<PathEnds>().is_err());
+    }
+
+    #[test]
+    fn invalid_paths() {
+        let cases: &[(&str, raw::Path, PathError)] = &[
+            (
+                "single point",
+                path(&[(0, 0)], 2),
+                PathError::TooFewPoints(1),
+            ),
+            (
+                "duplicate point",
+                path(&[(0, 0), (10, 0), (10, 0), (10, 10)], 2),
+                PathError::DuplicatePoint(raw::Point::new(10, 0)),
+            ),
+            (
+                "diagonal duplicate point",
+                path(&[(0, 0), (10, 10), (10, 10)], 2),
+                PathError::DuplicatePoint(raw::Point::new(10, 10)),
+            ),
+            (
+                "diagonal reversal",
+                path(&[(0, 0), (10, 10), (0, 0)], 2),
+                PathError::Reversal(raw::Point::new(10, 10)),
+            ),
+            (
+                "reversal",
+                path(&[(0, 0), (10, 0), (5, 0)], 2),
+                PathError::Reversal(raw::Point::new(10, 0)),
+            ),
+        ];
+
+        for (name, path, expected) in cases {
+            assert_eq!(
+                make_path_into_polygon(path, PathEnds::Flush).unwrap_err(),
+                *expected,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn extensions_past_the_end_segments() {
+        let cases: &[(&str, &[(isize, isize)], PathEnds, PathError)] = &[
+            (
+                "begin",
+                &[(0, 0), (10, 0), (10, 100)],
+                PathEnds::Custom { begin: -10, end: 0 },
+                PathError::ExtensionPastSegment(raw::Point::new(0, 0)),
+            ),
+            (
+                "end",
+                &[(0, 0), (100, 0), (100, 10)],
+                PathEnds::Custom { begin: 0, end: -25 },
+                PathError::ExtensionPastSegment(raw::Point::new(100, 10)),
+            ),
+            (
+                "diagonal end",
+                &[(0, 0), (30, 40)],
+                PathEnds::Custom { begin: 0, end: -60 },
+                PathError::ExtensionPastSegment(raw::Point::new(30, 40)),
+            ),
+            (
+                "both ends of one segment",
+                &[(0, 0), (100, 0)],
+                PathEnds::Custom {
+                    begin: -60,
+                    end: -60,
+                },
+                PathError::ExtensionPastSegment(raw::Point::new(100, 0)),
+            ),
+        ];
+
+        for (name, points, ends, expected) in cases {
+            assert_eq!(
+                make_path_into_polygon(&path(points, 20), *ends).unwrap_err(),
+                *expected,
+                "{name}"
+            );
+        }
+
+        // shortening an end segment is fine
+        let poly = make_path_into_polygon(
+            &path(&[(0, 0), (100, 0)], 20),
+            PathEnds::Custom {
+                begin: -10,
+                end: -40,
+            },
+        )
+        .unwrap();
+        assert_eq!(area(&poly), 1000.0);
+    }
 }
@@ -0,0 +1,223 @@
+use std::num::NonZeroU32;
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{
+            BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d, ImageCopyBuffer,
+            ImageDataLayout, MapMode,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use wgpu::Maintain;
+
+use crate::{
+    cli::CliArgs,
+    types::{AccumulationHandle, TileWalkCompleteEvent},
+};
+
+/// wgpu requires the rows of a texture to buffer copy to be aligned to this many bytes
+const COPY_BYTES_PER_ROW_ALIGNMENT: u32 = 256;
+
+/// Reads the accumulation texture back from the GPU once the tile walk is complete and saves it
+/// as a png, exiting afterwards when running headless
+pub struct AccumulationReadbackPlugin;
+
+struct ReadbackRequest {
+    handle: Handle<Image>,
+    width: u32,
+    height: u32,
+}
+
+struct ReadbackResult {
+    width: u32,
+    height: u32,
+    /// Tightly packed Bgra8 rows, top row first
+    data: Vec<u8>,
+}
+
+struct ReadbackChannels {
+    request_sender: Sender<ReadbackRequest>,
+    result_receiver: Receiver<ReadbackResult>,
+}
+
+struct RenderReadbackChannels {
+    request_receiver: Receiver<ReadbackRequest>,
+    result_sender: Sender<ReadbackResult>,
+}
+
+impl Plugin for AccumulationReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        let (request_sender, request_receiver) = unbounded::<ReadbackRequest>();
+        let (result_sender, result_receiver) = unbounded::<ReadbackResult>();
+
+        app.insert_resource(ReadbackChannels {
+            request_sender,
+            result_receiver,
+        })
+        .add_system(request_accumulation_readback_system)
+        .add_system(save_accumulation_png_system);
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(RenderReadbackChannels {
+                    request_receiver,
+                    result_sender,
+                })
+                .add_system_to_stage(RenderStage::Cleanup, readback_accumulation_system);
+        }
+    }
+}
+
+fn request_accumulation_readback_system(
+    args: Res<CliArgs>,
+    accumulation_image: Res<AccumulationHandle>,
+    images: Res<Assets<Image>>,
+    channels: Res<ReadbackChannels>,
+    mut tile_walk_complete_ev: EventReader<TileWalkCompleteEvent>,
+) {
+    for _ in tile_walk_complete_ev.iter() {
+        if args.png.is_some() {
+            info!("requesting accumulation texture readback");
+
+            let size = images
+                .get(&accumulation_image)
+                .unwrap()
+                .texture_descriptor
+                .size;
+
+            channels
+                .request_sender
+                .send(ReadbackRequest {
+                    handle: accumulation_image.clone(),
+                    width: size.width,
+                    height: size.height,
+                })
+                .unwrap();
+        }
+    }
+}
+
+fn save_accumulation_png_system(
+    args: Res<CliArgs>,
+    channels: Res<ReadbackChannels>,
+    mut app_exit_ev: EventWriter<AppExit>,
+) {
+    if let Ok(ReadbackResult {
+        width,
+        height,
+        mut data,
+    }) = channels.result_receiver.try_recv()
+    {
+        let path = args.png.as_ref().unwrap();
+
+        // the accumulation texture is Bgra8, png wants Rgba8
+        for px in data.chunks_exact_mut(4) {
+            px.swap(0, 2);
+        }
+
+        match image::save_buffer(path, &data, width, height, image::ColorType::Rgba8) {
+            Ok(()) => info!("saved accumulation texture to {path:?}"),
+            Err(e) => error!("failed to save accumulation texture to {path:?}: {e}"),
+        }
+
+        if args.headless {
+            app_exit_ev.send(AppExit);
+        }
+    }
+}
+
+fn readback_accumulation_system(
+    channels: Res<RenderReadbackChannels>,
+    gpu_images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    while let Ok(ReadbackRequest {
+        handle,
+        width,
+        height,
+    }) = channels.request_receiver.try_recv()
+    {
+        let gpu_image = match gpu_images.get(&handle) {
+            Some(gpu_image) => gpu_image,
+            None => {
+                error!("accumulation texture is not on the GPU, cannot read it back");
+                continue;
+            }
+        };
+
+        let unpadded_bytes_per_row = width * 4;
+        let padded_bytes_per_row = (unpadded_bytes_per_row + COPY_BYTES_PER_ROW_ALIGNMENT - 1)
+            / COPY_BYTES_PER_ROW_ALIGNMENT
+            * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("accumulation_readback_buffer"),
+            size: (padded_bytes_per_row * height) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("accumulation_readback_encoder"),
+        });
+
+        encoder.copy_texture_to_buffer(
+            gpu_image.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        render_queue.submit([encoder.finish()]);
+
+        let slice = buffer.slice(..);
+
+        let (map_sender, map_receiver) = bounded(1);
+        slice.map_async(MapMode::Read, move |result| {
+            map_sender.send(result).unwrap();
+        });
+
+        // block until the copy is done, this only happens once per render so we don't care
+        render_device.wgpu_device().poll(Maintain::Wait);
+
+        if let Err(e) = map_receiver.recv().unwrap() {
+            error!("failed to map accumulation readback buffer: {e:?}");
+            continue;
+        }
+
+        let mut data = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let padded = slice.get_mapped_range();
+            for row in padded.chunks_exact(padded_bytes_per_row as usize) {
+                data.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        channels
+            .result_sender
+            .send(ReadbackResult {
+                width,
+                height,
+                data,
+            })
+            .unwrap();
+    }
+}
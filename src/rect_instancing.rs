@@ -0,0 +1,438 @@
+use std::ops::Range;
+
+use bevy::{
+    core::cast_slice,
+    core_pipeline::core_2d::Transparent2d,
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    prelude::*,
+    render::{
+        render_phase::{
+            AddRenderCommand, DrawFunctions, RenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            BindGroupLayout, BlendState, Buffer, BufferInitDescriptor, BufferUsages,
+            ColorTargetState, ColorWrites, FragmentState, FrontFace, MultisampleState,
+            PipelineCache, PolygonMode, PrimitiveState, PrimitiveTopology,
+            RenderPipelineDescriptor, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            TextureFormat, VertexAttribute, VertexBufferLayout, VertexFormat, VertexState,
+            VertexStepMode,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::BevyDefault,
+        view::VisibleEntities,
+        RenderApp, RenderStage,
+    },
+    sprite::{Mesh2dPipeline, SetMesh2dViewBindGroup},
+    utils::{FloatOrd, HashMap},
+};
+
+/// Floats in a `RectInstance` as uploaded: position, size and colour
+const RECT_INSTANCE_FLOATS: usize = 3 + 2 + 4;
+
+/// Draws rectangles as instances of a quad, one instance buffer per entity, instead of
+/// tessellating a lyon path for each of them. The rectangles of every layer of an entity are
+/// drawn as a draw call of their own, sorted with the lyon shapes by their z. Instance buffers
+/// are kept across frames and only written when the entity's rectangles change.
+pub struct RectInstancingPlugin;
+
+impl Plugin for RectInstancingPlugin {
+    fn build(&self, app: &mut App) {
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_render_command::<Transparent2d, DrawRectInstances>()
+                .init_resource::<RectInstancingPipeline>()
+                .init_resource::<SpecializedRenderPipelines<RectInstancingPipeline>>()
+                .init_resource::<RectInstanceBuffers>()
+                .add_system_to_stage(RenderStage::Extract, extract_rect_instances)
+                .add_system_to_stage(RenderStage::Prepare, prepare_rect_instance_buffers)
+                .add_system_to_stage(RenderStage::Queue, queue_rect_instances);
+        }
+    }
+}
+
+/// An axis aligned rectangle in world space, filled with a flat colour
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RectInstance {
+    /// Bottom left corner, and z in the layer stack
+    pub position: Vec3,
+    pub size: Vec2,
+    /// Linear RGBA, as the lyon shapes are
+    pub color: [f32; 4],
+}
+
+impl RectInstance {
+    pub fn new(min: Vec2, size: Vec2, z: f32, color: Color) -> Self {
+        RectInstance {
+            position: min.extend(z),
+            size,
+            color: color.as_linear_rgba_f32(),
+        }
+    }
+
+    fn to_raw(self) -> [f32; RECT_INSTANCE_FLOATS] {
+        let [x, y, z] = self.position.to_array();
+        let [w, h] = self.size.to_array();
+        let [r, g, b, a] = self.color;
+        [x, y, z, w, h, r, g, b, a]
+    }
+}
+
+/// The instances drawing the rectangle from `min` to `max` at `z` like a lyon shape drawn with
+/// `DrawMode::Outlined`: the fill, unless it is clear, under four edges `width` wide centred on
+/// the outline
+pub fn outlined_rect(
+    min: Vec2,
+    max: Vec2,
+    z: f32,
+    fill: Color,
+    frame: Color,
+    width: f32,
+) -> Vec<RectInstance> {
+    let mut instances = Vec::with_capacity(5);
+
+    if fill.a() > 0.0 {
+        instances.push(RectInstance::new(min, max - min, z, fill));
+    }
+
+    let half = width / 2.0;
+    let (w, h) = (max.x - min.x, max.y - min.y);
+
+    // the bottom and top edges take the corners, the sides fit between them
+    instances.extend([
+        RectInstance::new(min - half, Vec2::new(w + width, width), z, frame),
+        RectInstance::new(
+            Vec2::new(min.x - half, max.y - half),
+            Vec2::new(w + width, width),
+            z,
+            frame,
+        ),
+        RectInstance::new(
+            Vec2::new(min.x - half, min.y + half),
+            Vec2::new(width, (h - width).max(0.0)),
+            z,
+            frame,
+        ),
+        RectInstance::new(
+            Vec2::new(max.x - half, min.y + half),
+            Vec2::new(width, (h - width).max(0.0)),
+            z,
+            frame,
+        ),
+    ]);
+
+    instances
+}
+
+/// The rectangles of one tile, sorted by z, along with the range of instances at each z
+#[derive(Component, Debug, Default, Clone)]
+pub struct RectInstances {
+    instances: Vec<RectInstance>,
+    layers: Vec<(f32, Range<u32>)>,
+}
+
+impl RectInstances {
+    /// Sort `instances` by z, keeping the order of those at the same z
+    pub fn new(mut instances: Vec<RectInstance>) -> Self {
+        instances.sort_by(|a, b| a.position.z.total_cmp(&b.position.z));
+
+        let mut layers: Vec<(f32, Range<u32>)> = vec![];
+
+        for (i, instance) in instances.iter().enumerate() {
+            let (z, i) = (instance.position.z, i as u32);
+            match layers.last_mut() {
+                Some((last_z, range)) if *last_z == z => range.end = i + 1,
+                _ => layers.push((z, i..i + 1)),
+            }
+        }
+
+        RectInstances { instances, layers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// The z of every layer with rectangles, and the range of instances drawing them
+    pub fn layers(&self) -> &[(f32, Range<u32>)] {
+        &self.layers
+    }
+}
+
+/// The layers of a visible `RectInstances`, extracted every frame
+#[derive(Component)]
+struct ExtractedRectLayers(Vec<(f32, Range<u32>)>);
+
+/// The instances of a `RectInstances` that changed since the last frame, to be uploaded
+#[derive(Component)]
+struct RectInstanceUpload(Vec<[f32; RECT_INSTANCE_FLOATS]>);
+
+/// The instance buffer of every entity with rectangles, by main world entity, and how many
+/// instances it has room for
+#[derive(Default)]
+struct RectInstanceBuffers(HashMap<Entity, (Buffer, usize)>);
+
+/// Extract the layers of visible rectangles, and the rectangles themselves only when they
+/// change, which is once per tile drawn rather than every frame the tile takes to render
+fn extract_rect_instances(
+    mut commands: Commands,
+    rect_instances_q: Query<(
+        Entity,
+        &RectInstances,
+        ChangeTrackers<RectInstances>,
+        &ComputedVisibility,
+    )>,
+) {
+    for (entity, rect_instances, tracker, visibility) in rect_instances_q.iter() {
+        if tracker.is_changed() {
+            let raw = rect_instances
+                .instances
+                .iter()
+                .map(|instance| instance.to_raw())
+                .collect();
+
+            commands
+                .get_or_spawn(entity)
+                .insert(RectInstanceUpload(raw));
+        }
+
+        if visibility.is_visible() && !rect_instances.is_empty() {
+            commands
+                .get_or_spawn(entity)
+                .insert(ExtractedRectLayers(rect_instances.layers().to_vec()));
+        }
+    }
+}
+
+/// Write changed rectangles into their entity's instance buffer, growing it if they don't fit
+fn prepare_rect_instance_buffers(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut buffers: ResMut<RectInstanceBuffers>,
+    upload_q: Query<(Entity, &RectInstanceUpload)>,
+) {
+    for (entity, RectInstanceUpload(raw)) in upload_q.iter() {
+        if raw.is_empty() {
+            continue;
+        }
+
+        match buffers.0.get(&entity) {
+            Some((buffer, capacity)) if *capacity >= raw.len() => {
+                render_queue.write_buffer(buffer, 0, cast_slice(raw));
+            }
+            _ => {
+                let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+                    label: Some("rect instance buffer"),
+                    contents: cast_slice(raw),
+                    usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                });
+                buffers.0.insert(entity, (buffer, raw.len()));
+            }
+        }
+    }
+}
+
+struct RectInstancingPipeline {
+    shader: Handle<Shader>,
+    /// The view uniform, as bound by `SetMesh2dViewBindGroup`
+    view_layout: BindGroupLayout,
+}
+
+impl FromWorld for RectInstancingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let shader = world
+            .resource::<AssetServer>()
+            .load("shaders/rect_instancing.wgsl");
+
+        RectInstancingPipeline {
+            shader,
+            view_layout: world.resource::<Mesh2dPipeline>().view_layout.clone(),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for RectInstancingPipeline {
+    /// Msaa samples
+    type Key = u32;
+
+    fn specialize(&self, msaa_samples: Self::Key) -> RenderPipelineDescriptor {
+        let float = std::mem::size_of::<f32>() as u64;
+
+        RenderPipelineDescriptor {
+            label: Some("rect_instancing_pipeline".into()),
+            layout: Some(vec![self.view_layout.clone()]),
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "vertex".into(),
+                buffers: vec![VertexBufferLayout {
+                    array_stride: RECT_INSTANCE_FLOATS as u64 * float,
+                    step_mode: VertexStepMode::Instance,
+                    attributes: vec![
+                        VertexAttribute {
+                            format: VertexFormat::Float32x3,
+                            offset: 0,
+                            shader_location: 0,
+                        },
+                        VertexAttribute {
+                            format: VertexFormat::Float32x2,
+                            offset: 3 * float,
+                            shader_location: 1,
+                        },
+                        VertexAttribute {
+                            format: VertexFormat::Float32x4,
+                            offset: 5 * float,
+                            shader_location: 2,
+                        },
+                    ],
+                }],
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: msaa_samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        }
+    }
+}
+
+/// Queue a draw per layer of every visible entity with rectangles, sorted by the layer's z
+fn queue_rect_instances(
+    draw_functions: Res<DrawFunctions<Transparent2d>>,
+    rect_instancing_pipeline: Res<RectInstancingPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<RectInstancingPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    msaa: Res<Msaa>,
+    rect_layers_q: Query<&ExtractedRectLayers>,
+    mut views: Query<(&VisibleEntities, &mut RenderPhase<Transparent2d>)>,
+) {
+    let draw_function = draw_functions.read().get_id::<DrawRectInstances>().unwrap();
+
+    let pipeline =
+        pipelines.specialize(&mut pipeline_cache, &rect_instancing_pipeline, msaa.samples);
+
+    for (visible_entities, mut phase) in views.iter_mut() {
+        for &entity in visible_entities.entities.iter() {
+            if let Ok(ExtractedRectLayers(layers)) = rect_layers_q.get(entity) {
+                for (z, range) in layers.iter() {
+                    phase.add(Transparent2d {
+                        sort_key: FloatOrd(*z),
+                        entity,
+                        pipeline,
+                        draw_function,
+                        batch_range: Some(range.clone()),
+                    });
+                }
+            }
+        }
+    }
+}
+
+type DrawRectInstances = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    DrawRectInstanceRange,
+);
+
+/// Draw the range of instances of the phase item, six vertices each
+struct DrawRectInstanceRange;
+
+impl RenderCommand<Transparent2d> for DrawRectInstanceRange {
+    type Param = SRes<RectInstanceBuffers>;
+
+    fn render<'w>(
+        _view: Entity,
+        item: &Transparent2d,
+        buffers: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let (buffer, range) = match (buffers.into_inner().0.get(&item.entity), &item.batch_range) {
+            (Some((buffer, _)), Some(range)) => (buffer, range.clone()),
+            _ => return RenderCommandResult::Failure,
+        };
+
+        pass.set_vertex_buffer(0, buffer.slice(..));
+        pass.draw(0..6, range);
+
+        RenderCommandResult::Success
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_are_grouped_by_z() {
+        let rect = |z| RectInstance::new(Vec2::ZERO, Vec2::ONE, z, Color::WHITE);
+
+        let rect_instances = RectInstances::new(vec![rect(2.0), rect(1.0), rect(2.0), rect(0.5)]);
+
+        assert_eq!(
+            rect_instances.layers(),
+            &[(0.5, 0..1), (1.0, 1..2), (2.0, 2..4)]
+        );
+    }
+
+    #[test]
+    fn outline_covers_the_edges_once() {
+        let (min, max) = (Vec2::new(0.0, 0.0), Vec2::new(10.0, 4.0));
+        let instances = outlined_rect(min, max, 0.0, Color::NONE, Color::RED, 2.0);
+
+        // no fill for a clear fill colour
+        assert_eq!(instances.len(), 4);
+
+        let covering = |p: Vec2| {
+            instances
+                .iter()
+                .filter(|r| {
+                    let (lo, hi) = (r.position.truncate(), r.position.truncate() + r.size);
+                    p.x > lo.x && p.x < hi.x && p.y > lo.y && p.y < hi.y
+                })
+                .count()
+        };
+
+        // corners, edges and just inside and outside the stroke
+        for p in [
+            (0.0, 0.0),
+            (10.0, 4.0),
+            (5.0, 0.0),
+            (0.0, 2.0),
+            (10.5, 2.0),
+            (5.0, 3.5),
+        ] {
+            assert_eq!(covering(p.into()), 1, "{p:?}");
+        }
+        for p in [(5.0, 2.0), (-1.5, 2.0), (5.0, 5.5)] {
+            assert_eq!(covering(p.into()), 0, "{p:?}");
+        }
+
+        let filled = outlined_rect(min, max, 0.0, Color::BLUE, Color::RED, 2.0);
+        assert_eq!(filled.len(), 5);
+        assert_eq!(filled[0].size, max - min);
+    }
+}
@@ -0,0 +1,232 @@
+use bevy::{prelude::*, render::mesh::Indices, sprite::Mesh2dHandle, utils::HashMap};
+
+use crate::types::TiledRendererSettings;
+
+/// Where the mesh of a lyon entity comes from, so that the meshes lyon tessellates for shape
+/// outlines are cached under the shape they were tessellated for
+#[derive(Component, Debug, Clone)]
+pub enum CachedShape {
+    /// Patterns draw geometry clipped to their tile and aren't cached
+    Uncached,
+    /// The outline of the shape at `FlattenedElems` index `idx` was handed to lyon, its mesh is
+    /// the first one to replace `stale`, the mesh the entity had until then
+    Tessellating { idx: usize, stale: Handle<Mesh> },
+    /// The entity draws this cached mesh
+    Cached(Handle<Mesh>),
+}
+
+impl Default for CachedShape {
+    fn default() -> Self {
+        CachedShape::Uncached
+    }
+}
+
+#[derive(Debug)]
+struct CachedMesh {
+    mesh: Handle<Mesh>,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Meshes lyon tessellated for the outlines of shapes, by `FlattenedElems` index, so that shapes
+/// spanning several tiles and tiles rendered again aren't tessellated more than once. Meshes
+/// are in the shape's local coordinates, placed by the transform of the entity drawing them.
+/// The least recently used meshes are evicted once they take more than the settings'
+/// `tessellation_cache_bytes`.
+#[derive(Debug, Default)]
+pub struct TessellationCache {
+    meshes: HashMap<usize, CachedMesh>,
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl TessellationCache {
+    /// The mesh of shape `idx`, if it has been tessellated
+    pub fn get(&mut self, idx: usize) -> Option<Handle<Mesh>> {
+        self.clock += 1;
+
+        match self.meshes.get_mut(&idx) {
+            Some(cached) => {
+                cached.last_used = self.clock;
+                self.hits += 1;
+                Some(cached.mesh.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache `mesh`, of `bytes` bytes, for shape `idx` and evict the least recently used meshes
+    /// until the cache holds at most `budget` bytes
+    pub fn insert(&mut self, idx: usize, mesh: Handle<Mesh>, bytes: usize, budget: usize) {
+        self.clock += 1;
+
+        if let Some(old) = self.meshes.insert(
+            idx,
+            CachedMesh {
+                mesh,
+                bytes,
+                last_used: self.clock,
+            },
+        ) {
+            self.bytes -= old.bytes;
+        }
+        self.bytes += bytes;
+
+        if self.bytes <= budget {
+            return;
+        }
+
+        let mut by_age = self
+            .meshes
+            .iter()
+            .map(|(idx, cached)| (cached.last_used, *idx))
+            .collect::<Vec<(u64, usize)>>();
+        by_age.sort_unstable();
+
+        for (_, idx) in by_age {
+            if self.bytes <= budget {
+                break;
+            }
+            let cached = self.meshes.remove(&idx).unwrap();
+            self.bytes -= cached.bytes;
+        }
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        self.meshes.contains_key(&idx)
+    }
+
+    /// Memory taken by the vertex and index buffers of the cached meshes, in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.meshes.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Size of the vertex and index buffers of `mesh`, in bytes
+pub fn mesh_bytes(mesh: &Mesh) -> usize {
+    let vertices = mesh.count_vertices() * mesh.get_vertex_size() as usize;
+
+    let indices = match mesh.indices() {
+        Some(Indices::U16(indices)) => indices.len() * std::mem::size_of::<u16>(),
+        Some(Indices::U32(indices)) => indices.len() * std::mem::size_of::<u32>(),
+        None => 0,
+    };
+
+    vertices + indices
+}
+
+/// Cache the meshes lyon has tessellated for shape outlines, under the shape each was
+/// tessellated for. Runs after lyon's stage, which also tessellates entities spawned with a
+/// cached mesh, so those get their cached mesh back before they are drawn.
+pub fn cache_tessellations_system(
+    mut cache: ResMut<TessellationCache>,
+    meshes: Res<Assets<Mesh>>,
+    settings: Res<TiledRendererSettings>,
+    mut shape_q: Query<(&mut CachedShape, &mut Mesh2dHandle)>,
+) {
+    let mut cached_any = false;
+
+    for (mut cached_shape, mut mesh_handle) in shape_q.iter_mut() {
+        match &*cached_shape {
+            CachedShape::Tessellating { idx, stale } if mesh_handle.0 != *stale => {
+                if let Some(mesh) = meshes.get(&mesh_handle.0) {
+                    cache.insert(
+                        *idx,
+                        mesh_handle.0.clone(),
+                        mesh_bytes(mesh),
+                        settings.tessellation_cache_bytes,
+                    );
+                    cached_any = true;
+                }
+                *cached_shape = CachedShape::Cached(mesh_handle.0.clone());
+            }
+            CachedShape::Cached(mesh) if mesh_handle.0 != *mesh => {
+                mesh_handle.0 = mesh.clone();
+            }
+            _ => {}
+        }
+    }
+
+    if cached_any {
+        debug!(
+            "tessellation cache: {} meshes, {} bytes, {} hits, {} misses",
+            cache.len(),
+            cache.bytes(),
+            cache.hits,
+            cache.misses
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::HandleId;
+
+    use super::*;
+
+    fn mesh() -> Handle<Mesh> {
+        Handle::weak(HandleId::random::<Mesh>())
+    }
+
+    #[test]
+    fn least_recently_used_meshes_are_evicted() {
+        let mut cache = TessellationCache::default();
+
+        cache.insert(0, mesh(), 100, 300);
+        cache.insert(1, mesh(), 100, 300);
+        cache.insert(2, mesh(), 100, 300);
+        assert_eq!(cache.bytes(), 300);
+
+        // 0 is used again, so 1 goes first
+        assert!(cache.get(0).is_some());
+        cache.insert(3, mesh(), 150, 300);
+
+        assert!(cache.contains(0));
+        assert!(!cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+        assert_eq!(cache.bytes(), 250);
+        assert!(cache.get(1).is_none());
+    }
+
+    #[test]
+    fn replacing_a_mesh_keeps_the_count_right() {
+        let mut cache = TessellationCache::default();
+
+        cache.insert(0, mesh(), 100, 1000);
+        cache.insert(0, mesh(), 40, 1000);
+        assert_eq!((cache.len(), cache.bytes()), (1, 40));
+
+        // a mesh larger than the budget doesn't stay
+        cache.insert(1, mesh(), 2000, 1000);
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn mesh_bytes_counts_vertices_and_indices() {
+        let mut mesh = Mesh::new(bevy::render::mesh::PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0f32; 3]; 4]);
+        mesh.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+
+        assert_eq!(mesh_bytes(&mesh), 4 * 12 + 6 * 4);
+    }
+}
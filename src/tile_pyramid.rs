@@ -0,0 +1,481 @@
+use bevy::{prelude::*, sprite::Anchor, utils::HashSet};
+use geo::Intersects;
+use layout21::raw;
+
+use crate::{
+    geo_shape, label_extents,
+    path_to_poly::PathEnds,
+    render_target_image,
+    types::{
+        FlattenedElems, FlattenedLabels, GeoRect, HiddenLayers, LabelFont, MainCamera,
+        PyramidSprite, PyramidTile, SendTilesLabel, Tile, TileGrid, TileKey, TilePyramid,
+        TiledRendererSettings, Tilemap, ViewRect, MAIN_CAMERA_LAYER,
+    },
+};
+
+/// Renders the layout again at finer tile sizes as the main camera zooms in, so that zooming
+/// past the resolution of the accumulation texture stays sharp. The tiles in view at the level
+/// matching the camera's scale are binned from their parents and rendered on demand, each into
+/// a texture of its own shown over the accumulation texture. Coarser levels show through until
+/// they are done.
+pub struct TilePyramidPlugin;
+
+impl Plugin for TilePyramidPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TilePyramid>()
+            .add_system(update_pyramid_system.before(SendTilesLabel));
+    }
+}
+
+/// The pyramid level with at least one tile texture pixel per screen pixel when the main
+/// camera is at `scale`, which is in accumulation texture pixels per screen pixel
+pub fn zoom_level(scale: f32, max_level: u8) -> u8 {
+    if scale >= 1.0 {
+        return 0;
+    }
+
+    (-scale.log2()).ceil().min(max_level as f32) as u8
+}
+
+/// Extents of the quarter of `parent` that the tile at `index` is, one level above it
+pub fn child_extents(parent: &GeoRect, index: (u32, u32)) -> GeoRect {
+    let (min, max) = (parent.min(), parent.max());
+
+    let mid_x = min.x + (max.x - min.x) / 2;
+    let mid_y = min.y + (max.y - min.y) / 2;
+
+    let (x0, x1) = if index.0 % 2 == 0 {
+        (min.x, mid_x)
+    } else {
+        (mid_x, max.x)
+    };
+    let (y0, y1) = if index.1 % 2 == 0 {
+        (min.y, mid_y)
+    } else {
+        (mid_y, max.y)
+    };
+
+    GeoRect::new((x0, y0), (x1, y1))
+}
+
+/// Keys of the tiles at `level` overlapping the world space `view`, row major from the bottom
+/// left
+pub fn visible_tiles(grid: &TileGrid, level: u8, view: &GeoRect) -> Vec<TileKey> {
+    let (view_min, view_max) = (view.min(), view.max());
+
+    // tiles only touching the view along an edge aren't in it, nor are empty tiles at levels
+    // finer than the database unit
+    let overlaps = |extents: &GeoRect| {
+        let (min, max) = (extents.min(), extents.max());
+        min.x < view_max.x && view_min.x < max.x && min.y < view_max.y && view_min.y < max.y
+    };
+
+    let (x_range, y_range) = grid.tile_range((view_min.x, view_min.y), (view_max.x, view_max.y));
+
+    let mut stack = vec![];
+
+    for iy in y_range {
+        for ix in x_range.clone() {
+            let extents = grid.tile_extents(ix, iy);
+            if overlaps(&extents) {
+                stack.push((TileKey::new(0, (ix, iy)), extents));
+            }
+        }
+    }
+
+    let mut tiles = vec![];
+
+    while let Some((key, extents)) = stack.pop() {
+        if key.level == level {
+            tiles.push(key);
+            continue;
+        }
+
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let index = (key.index.0 * 2 + dx, key.index.1 * 2 + dy);
+            let child = child_extents(&extents, index);
+
+            if overlaps(&child) {
+                stack.push((TileKey::new(key.level + 1, index), child));
+            }
+        }
+    }
+
+    tiles.sort_by_key(|key| (key.index.1, key.index.0));
+
+    tiles
+}
+
+/// Bin the shapes and labels of `parent` that fall in `extents`, one of its quarters, the same
+/// way `import_cell_shapes` and `import_cell_labels` bin the tilemap
+pub fn bin_child_tile(
+    parent: &Tile,
+    extents: GeoRect,
+    elems: &[raw::Element],
+    labels: &[raw::TextElement],
+    path_ends: PathEnds,
+    label_height: u32,
+) -> Tile {
+    let shapes = parent
+        .shapes
+        .iter()
+        .copied()
+        .filter(|idx| {
+            // paths that can't be converted were never binned into the parent
+            geo_shape(&elems[*idx].inner, path_ends)
+                .map(|shape| shape.intersects(&extents))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    let labels = parent
+        .labels
+        .iter()
+        .copied()
+        .filter(|idx| label_extents(&labels[*idx], label_height).intersects(&extents))
+        .collect();
+
+    Tile {
+        extents,
+        shapes,
+        labels,
+    }
+}
+
+/// Bin the tile at `key` and any of its ancestors that aren't binned yet, marking it as used in
+/// `generation`
+fn bin_tile(
+    pyramid: &mut TilePyramid,
+    tilemap: &Tilemap,
+    elems: &[raw::Element],
+    labels: &[raw::TextElement],
+    settings: &TiledRendererSettings,
+    key: TileKey,
+    generation: u64,
+) {
+    if key.level == 0 {
+        return;
+    }
+
+    if let Some(tile) = pyramid.tiles.get_mut(&key) {
+        tile.last_used = generation;
+        return;
+    }
+
+    bin_tile(
+        pyramid,
+        tilemap,
+        elems,
+        labels,
+        settings,
+        key.parent(),
+        generation,
+    );
+
+    let parent = if key.level == 1 {
+        tilemap.get(&key.parent().index)
+    } else {
+        pyramid.tiles.get(&key.parent()).map(|parent| &parent.tile)
+    };
+
+    let tile = match parent {
+        Some(parent) => bin_child_tile(
+            parent,
+            child_extents(&parent.extents, key.index),
+            elems,
+            labels,
+            settings.path_ends,
+            settings.label_height,
+        ),
+        None => return,
+    };
+
+    pyramid.tiles.insert(
+        key,
+        PyramidTile {
+            tile,
+            image: None,
+            sprite: None,
+            queued: false,
+            rendered: false,
+            last_used: generation,
+        },
+    );
+}
+
+/// Despawn the sprite of `tile` and free its texture
+fn release_tile(commands: &mut Commands, images: &mut Assets<Image>, tile: PyramidTile) {
+    if let Some(sprite) = tile.sprite {
+        commands.entity(sprite).despawn();
+    }
+
+    if let Some(image) = tile.image {
+        images.remove(image);
+    }
+}
+
+/// Bring the pyramid in line with the main camera: bin and queue the tiles in view at the level
+/// matching its scale, show the sprites of the levels up to it, and evict tiles out of view
+/// once the cache is full
+fn update_pyramid_system(
+    mut commands: Commands,
+    mut pyramid: ResMut<TilePyramid>,
+    mut images: ResMut<Assets<Image>>,
+    tilemap: Res<Tilemap>,
+    grid: Res<TileGrid>,
+    flattened_elems: Res<FlattenedElems>,
+    flattened_labels: Res<FlattenedLabels>,
+    hidden_layers: Res<HiddenLayers>,
+    label_font: Res<LabelFont>,
+    settings: Res<TiledRendererSettings>,
+    camera_q: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    changed_camera_q: Query<
+        (),
+        (
+            Or<(Changed<Transform>, Changed<OrthographicProjection>)>,
+            With<MainCamera>,
+        ),
+    >,
+    mut sprite_q: Query<&mut Visibility, With<PyramidSprite>>,
+    mut generation: Local<u64>,
+) {
+    let pyramid = &mut *pyramid;
+
+    let mut pyramid_changed = false;
+
+    if tilemap.is_changed() {
+        // a new layout, nothing binned or rendered for the last one applies
+        for (_, tile) in pyramid.tiles.drain() {
+            release_tile(&mut commands, &mut images, tile);
+        }
+        pyramid.queue.clear();
+        pyramid_changed = true;
+    } else if hidden_layers.is_changed() {
+        // rendered tiles keep showing until they are rendered again with the new layers
+        for tile in pyramid.tiles.values_mut() {
+            tile.rendered = false;
+        }
+        pyramid_changed = true;
+    }
+
+    let (transform, projection) = match camera_q.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    if (!pyramid_changed && changed_camera_q.is_empty()) || grid.num_x == 0 || grid.num_y == 0 {
+        return;
+    }
+
+    *generation += 1;
+
+    let level = zoom_level(projection.scale, settings.max_zoom_level);
+
+    let ViewRect { min, max } = ViewRect::from_camera(transform, projection);
+    let view = GeoRect::new(
+        grid.view_to_world((min.x, min.y), settings.tile_size_in_px),
+        grid.view_to_world((max.x, max.y), settings.tile_size_in_px),
+    );
+
+    let visible = if level == 0 {
+        vec![]
+    } else {
+        visible_tiles(&grid, level, &view)
+    };
+
+    let draw_labels = label_font.0.is_some();
+
+    for key in visible.iter() {
+        bin_tile(
+            pyramid,
+            &tilemap,
+            &flattened_elems,
+            &flattened_labels,
+            &settings,
+            *key,
+            *generation,
+        );
+
+        let tile = match pyramid.tiles.get_mut(key) {
+            Some(tile) => tile,
+            None => continue,
+        };
+
+        if tile.tile.is_empty(draw_labels) {
+            continue;
+        }
+
+        if tile.image.is_none() {
+            let image = images.add(render_target_image(
+                settings.tile_size_in_px,
+                "PYRAMID_TILE_TEXTURE",
+            ));
+
+            let (min, max) = (tile.tile.extents.min(), tile.tile.extents.max());
+            let (x0, y0) = grid.world_to_view((min.x, min.y), settings.tile_size_in_px);
+            let (x1, y1) = grid.world_to_view((max.x, max.y), settings.tile_size_in_px);
+
+            // above the accumulation texture, finer levels over coarser ones
+            let sprite = commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::new(x1 - x0, y1 - y0)),
+                        anchor: Anchor::BottomLeft,
+                        ..default()
+                    },
+                    texture: image.clone(),
+                    transform: Transform::from_translation(Vec3::new(
+                        x0,
+                        y0,
+                        1.0 + key.level as f32,
+                    )),
+                    ..default()
+                })
+                .insert(MAIN_CAMERA_LAYER)
+                .insert(PyramidSprite)
+                .id();
+
+            tile.image = Some(image);
+            tile.sprite = Some(sprite);
+        }
+
+        if !tile.rendered && !tile.queued {
+            tile.queued = true;
+            pyramid.queue.push_back(*key);
+        }
+    }
+
+    // tiles that went out of view before their turn are queued again when they come back
+    let in_view = visible.iter().copied().collect::<HashSet<TileKey>>();
+    let tiles = &mut pyramid.tiles;
+    pyramid.queue.retain(|key| {
+        let keep = in_view.contains(key);
+        if !keep {
+            if let Some(tile) = tiles.get_mut(key) {
+                tile.queued = false;
+            }
+        }
+        keep
+    });
+
+    // closest to the centre of the view first, like the walk over the tilemap
+    let center = view.center();
+    let tiles = &pyramid.tiles;
+    pyramid.queue.make_contiguous().sort_by(|a, b| {
+        let distance = |key: &TileKey| {
+            let c = tiles[key].tile.extents.center();
+            ((c.x - center.x) as f64).hypot((c.y - center.y) as f64)
+        };
+        distance(a).total_cmp(&distance(b))
+    });
+
+    for (key, tile) in pyramid.tiles.iter() {
+        if let Some(mut vis) = tile.sprite.and_then(|sprite| sprite_q.get_mut(sprite).ok()) {
+            vis.is_visible = key.level <= level;
+        }
+    }
+
+    let num_tiles = pyramid.tiles.len();
+
+    if num_tiles > settings.pyramid_cache_tiles {
+        let mut out_of_view = pyramid
+            .tiles
+            .iter()
+            .filter(|(key, tile)| tile.last_used < *generation && !pyramid.in_flight.contains(key))
+            .map(|(key, tile)| (tile.last_used, *key))
+            .collect::<Vec<(u64, TileKey)>>();
+
+        // least recently in view first
+        out_of_view.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+        for (_, key) in out_of_view
+            .into_iter()
+            .take(num_tiles - settings.pyramid_cache_tiles)
+        {
+            let tile = pyramid.tiles.remove(&key).unwrap();
+            release_tile(&mut commands, &mut images, tile);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2x2 grid of 100x100 tiles with its origin at (0, 0)
+    fn grid() -> TileGrid {
+        TileGrid {
+            origin: (0, 0),
+            tile_size: 100,
+            num_x: 2,
+            num_y: 2,
+        }
+    }
+
+    #[test]
+    fn zoom_level_follows_scale() {
+        assert_eq!(zoom_level(8.5, 6), 0);
+        assert_eq!(zoom_level(1.0, 6), 0);
+        assert_eq!(zoom_level(0.5, 6), 1);
+        assert_eq!(zoom_level(0.4, 6), 2);
+        assert_eq!(zoom_level(0.001, 6), 6);
+    }
+
+    #[test]
+    fn children_split_their_parent() {
+        let parent = GeoRect::new((0, 0), (101, 100));
+
+        assert_eq!(
+            child_extents(&parent, (0, 0)),
+            GeoRect::new((0, 0), (50, 50))
+        );
+        assert_eq!(
+            child_extents(&parent, (1, 0)),
+            GeoRect::new((50, 0), (101, 50))
+        );
+        assert_eq!(
+            child_extents(&parent, (2, 1)),
+            GeoRect::new((0, 50), (50, 100))
+        );
+        assert_eq!(
+            child_extents(&parent, (3, 3)),
+            GeoRect::new((50, 50), (101, 100))
+        );
+    }
+
+    #[test]
+    fn visible_tiles_descend_into_the_view() {
+        let grid = grid();
+
+        // the top right quarter of tile (0, 0)
+        let view = GeoRect::new((60, 60), (90, 90));
+        assert_eq!(
+            visible_tiles(&grid, 1, &view),
+            vec![TileKey::new(1, (1, 1))]
+        );
+
+        // straddling the middle of the grid
+        let view = GeoRect::new((90, 90), (110, 110));
+        assert_eq!(
+            visible_tiles(&grid, 2, &view),
+            vec![
+                TileKey::new(2, (3, 3)),
+                TileKey::new(2, (4, 3)),
+                TileKey::new(2, (3, 4)),
+                TileKey::new(2, (4, 4)),
+            ]
+        );
+
+        // outside the grid
+        let view = GeoRect::new((300, 300), (400, 400));
+        assert!(visible_tiles(&grid, 1, &view).is_empty());
+    }
+
+    #[test]
+    fn view_and_world_round_trip() {
+        let grid = grid();
+
+        assert_eq!(grid.world_to_view((50, 100), 64), (32.0, 64.0));
+        assert_eq!(grid.view_to_world((32.0, 64.0), 64), (50, 100));
+    }
+}
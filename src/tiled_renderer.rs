@@ -1,29 +1,38 @@
+use std::marker::PhantomData;
+
 use bevy::{
     asset::HandleId,
+    ecs::system::SystemParam,
     prelude::*,
     reflect::TypeUuid,
     render::{
-        camera::Viewport,
+        camera::{RenderTarget, Viewport},
         mesh::PrimitiveTopology,
         render_resource::{AsBindGroup, ShaderRef},
         renderer::RenderQueue,
+        view::RenderLayers,
     },
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle},
+    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, Mesh2dHandle},
+    utils::HashSet,
 };
 use bevy_prototype_lyon::prelude::*;
 use crossbeam_channel::bounded;
 
 use crate::{
-    path_to_poly::make_path_into_polygon,
+    fill_pattern::{dot_centers, hatch_segments, FillPattern},
+    path_to_poly::{make_path_into_polygon, PathEnds, PathError},
+    rect_instancing::{outlined_rect, RectInstances, RectInstancingPlugin},
+    tessellation_cache::{cache_tessellations_system, CachedShape, TessellationCache},
     types::{
-        DrawTileEvent, FlattenedElems, Layers, LibLayers, LyonShape, LyonShapeBundle,
-        RenderingCompleteEvent, RenderingDoneChannel, Tilemap, TilemapLowerLeft, ALPHA,
-        DOWNSCALING_PASS_LAYER, TILE_SIZE_IN_PX, WIDTH,
+        AccumulationHandle, DrawTile, DrawTileEvent, FlattenedElems, FlattenedLabels, GeoRect,
+        HiddenLayers, LabelFont, LayerStyle, Layers, LibLayers, LiveView, LyonShape,
+        LyonShapeBundle, RenderSlot, RenderingCompleteEvent, RenderingDoneChannel, Tile, TileGrid,
+        TileLabel, TilePyramid, TiledRendererSettings, Tilemap, WorldToRender,
     },
 };
 use crate::{
     types::{AccumulationCam, HiResCam},
-    HiResHandle,
+    HiResHandles,
 };
 use layout21::raw;
 
@@ -34,11 +43,17 @@ enum TiledRenderStage {
     SpawnCameras,
     SpawnShapes,
     Despawn,
+    /// After lyon's stage has tessellated the shapes spawned this frame
+    Tessellated,
 }
 
 impl Plugin for TiledRendererPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ShapePlugin)
+        app.init_resource::<TiledRendererSettings>()
+            .init_resource::<LabelFont>()
+            .init_resource::<TessellationCache>()
+            .add_plugin(ShapePlugin)
+            .add_plugin(RectInstancingPlugin)
             .add_plugin(Material2dPlugin::<PostProcessingMaterial>::default())
             .insert_resource({
                 let (sender, receiver) = bounded::<()>(1);
@@ -59,13 +74,74 @@ impl Plugin for TiledRendererPlugin {
                 TiledRenderStage::Despawn,
                 SystemStage::parallel(),
             )
+            .add_stage_before(
+                CoreStage::PostUpdate,
+                TiledRenderStage::Tessellated,
+                SystemStage::parallel(),
+            )
             .add_system_to_stage(TiledRenderStage::SpawnCameras, spawn_cameras_system)
             .add_system_to_stage(TiledRenderStage::SpawnShapes, spawn_shapes_system)
+            .add_system_to_stage(TiledRenderStage::SpawnShapes, spawn_labels_system)
+            .add_system_to_stage(TiledRenderStage::Tessellated, cache_tessellations_system)
             .add_system_to_stage(TiledRenderStage::Despawn, despawn_system);
         // .add_system(debug_image_handles);
     }
 }
 
+/// Every tile that can be drawn, from the tilemap, the pyramid above it and the live view, and
+/// the texture each of them is rendered into
+#[derive(SystemParam)]
+pub struct RenderTiles<'w, 's> {
+    tilemap: Res<'w, Tilemap>,
+    pyramid: Res<'w, TilePyramid>,
+    live_view: Res<'w, LiveView>,
+    grid: Res<'w, TileGrid>,
+    settings: Res<'w, TiledRendererSettings>,
+    accumulation_image: Res<'w, AccumulationHandle>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> RenderTiles<'w, 's> {
+    /// The tile `key` draws, unless it was evicted from the pyramid or belongs to a live view
+    /// render that has been replaced since it was sent
+    pub fn get(&self, key: &DrawTile) -> Option<&Tile> {
+        match key {
+            DrawTile::Pyramid(key) if key.level == 0 => self.tilemap.get(&key.index),
+            DrawTile::Pyramid(key) => self.pyramid.tiles.get(key).map(|tile| &tile.tile),
+            DrawTile::LiveView { render, index } if *render == self.live_view.render => {
+                self.live_view.tilemap.get(index)
+            }
+            DrawTile::LiveView { .. } => None,
+        }
+    }
+
+    /// The texture `key` is rendered into, and the pixel position of its top left corner in it
+    pub fn target(&self, key: &DrawTile) -> Option<(Handle<Image>, UVec2)> {
+        let tile_size_in_px = self.settings.tile_size_in_px;
+
+        match key {
+            DrawTile::Pyramid(key) if key.level == 0 => {
+                let (ix, iy) = key.index;
+                let (px, py) = self.grid.texture_position(ix, iy, tile_size_in_px);
+                Some(((**self.accumulation_image).clone(), UVec2::new(px, py)))
+            }
+            DrawTile::Pyramid(key) => {
+                let image = self.pyramid.tiles.get(key)?.image.clone()?;
+                Some((image, UVec2::ZERO))
+            }
+            DrawTile::LiveView { render, index } if *render == self.live_view.render => {
+                let (px, py) =
+                    self.live_view
+                        .grid
+                        .texture_position(index.0, index.1, tile_size_in_px);
+                Some((self.live_view.image.clone()?, UVec2::new(px, py)))
+            }
+            DrawTile::LiveView { .. } => None,
+        }
+    }
+}
+
 #[allow(unused)]
 fn debug_image_handles(q: Query<&Handle<Image>>) {
     for h in q.iter() {
@@ -80,176 +156,333 @@ fn debug_image_handles(q: Query<&Handle<Image>>) {
     }
 }
 
+/// Outline of `el` in world space, with paths converted to polygons with `path_ends`. Rects and
+/// polygons with the same corners have the same outline, and are drawn the same.
+pub fn world_outline(el: &raw::Element, path_ends: PathEnds) -> Result<Vec<(f64, f64)>, PathError> {
+    match &el.inner {
+        raw::Shape::Rect(r) => {
+            let raw::Rect { p0, p1 } = r;
+            let (xmin, ymin, xmax, ymax) = (p0.x as f64, p0.y as f64, p1.x as f64, p1.y as f64);
+            Ok(vec![(xmin, ymin), (xmax, ymin), (xmax, ymax), (xmin, ymax)])
+        }
+        raw::Shape::Polygon(poly) => Ok(poly
+            .points
+            .iter()
+            .map(|p| (p.x as f64, p.y as f64))
+            .collect()),
+        raw::Shape::Path(path) => Ok(make_path_into_polygon(path, path_ends)?
+            .exterior()
+            .points()
+            .map(|p| (p.x(), p.y()))
+            .collect()),
+    }
+}
+
 fn spawn_shapes_system(
     mut commands: Commands,
-    tilemap: Res<Tilemap>,
+    tiles: RenderTiles,
     flattened_elems: Res<FlattenedElems>,
     lib_layers: Res<LibLayers>,
     layers: Res<Layers>,
+    settings: Res<TiledRendererSettings>,
+    hidden_layers: Res<HiddenLayers>,
+    world_to_render: Res<WorldToRender>,
+    mut tessellation_cache: ResMut<TessellationCache>,
     mut draw_ev: EventReader<DrawTileEvent>,
     mut existing_lyon_shapes: Query<
         (
             &mut bevy_prototype_lyon::entity::Path,
+            &mut DrawMode,
+            &mut Mesh2dHandle,
+            &mut CachedShape,
             &mut Transform,
             &mut Visibility,
+            &mut RenderLayers,
         ),
         With<LyonShape>,
     >,
+    mut rect_batches: Query<(&RenderSlot, &mut RectInstances, &mut Visibility), Without<LyonShape>>,
 ) {
-    for DrawTileEvent(key) in draw_ev.iter() {
-        let tile = tilemap.get(key).unwrap();
+    // the tiles of a batch share the pool of hidden shapes, so it is walked once per frame
+    let mut existing_shapes_iter = existing_lyon_shapes.iter_mut();
+
+    // the cached meshes were tessellated with the old styles, for other shapes or in another
+    // render space
+    if layers.is_changed() || flattened_elems.is_changed() || world_to_render.is_changed() {
+        tessellation_cache.clear();
+    }
+
+    // reuse a hidden shape entity left over from a previous batch if there is one. Given the
+    // cached mesh of shape `idx`, the entity takes it as is and lyon leaves its path alone,
+    // otherwise the entity records which shape lyon is tessellating for so the mesh can be
+    // cached.
+    let mut spawn_or_reuse = |mut bundle: LyonShapeBundle,
+                              layer: RenderLayers,
+                              idx: Option<usize>,
+                              cached_mesh: Option<Handle<Mesh>>| {
+        if let Some((
+            mut existing_path,
+            mut existing_mode,
+            mut existing_mesh,
+            mut existing_cached_shape,
+            mut existing_transform,
+            mut vis,
+            mut existing_layer,
+        )) = existing_shapes_iter.next()
+        {
+            *existing_cached_shape = match (idx, cached_mesh) {
+                (_, Some(mesh)) => {
+                    existing_mesh.0 = mesh.clone();
+                    CachedShape::Cached(mesh)
+                }
+                (idx, None) => {
+                    *existing_path = bundle.lyon.path;
+                    *existing_mode = bundle.lyon.mode;
+                    match idx {
+                        Some(idx) => CachedShape::Tessellating {
+                            idx,
+                            stale: existing_mesh.0.clone(),
+                        },
+                        None => CachedShape::Uncached,
+                    }
+                }
+            };
+            *existing_transform = bundle.lyon.transform;
+            *existing_layer = layer;
+            vis.is_visible = true;
+        } else {
+            let cached_shape = match (idx, cached_mesh) {
+                (_, Some(mesh)) => {
+                    // lyon tessellates every new entity, an empty path keeps that cheap until
+                    // cache_tessellations_system puts the cached mesh back
+                    bundle.lyon.path = GeometryBuilder::new()
+                        .build(
+                            DrawMode::Fill(FillMode::color(Color::NONE)),
+                            Transform::default(),
+                        )
+                        .path;
+                    bundle.lyon.mesh = Mesh2dHandle(mesh.clone());
+                    CachedShape::Cached(mesh)
+                }
+                (Some(idx), None) => CachedShape::Tessellating {
+                    idx,
+                    stale: bundle.lyon.mesh.0.clone(),
+                },
+                (None, None) => CachedShape::Uncached,
+            };
+
+            commands
+                .spawn_bundle(bundle)
+                .insert(layer)
+                .insert(cached_shape);
+        }
+    };
+
+    // slots drawing rectangles for the first time, their batches are spawned once the shapes are
+    let mut new_rect_batches = vec![];
+
+    for DrawTileEvent { tile: key, slot } in draw_ev.iter() {
+        // spawn_cameras_system warns about tiles dropped before they were drawn
+        let tile = match tiles.get(key) {
+            Some(tile) => tile,
+            None => continue,
+        };
+        let world_to_render = world_to_render.at_tile(&tile.extents);
+        let render_layer = slot.shape_layer();
+
+        // the rectangles of the tile are drawn as instances of a quad instead of lyon shapes
+        let mut rects = vec![];
 
         // let read_lib_layers = lib_layers.read().unwrap();
         // let mut bundle_vec = Vec::with_capacity(tile.shapes.len());
 
         info!("Num shapes in this tile: {}", tile.shapes.len());
 
-        let mut existing_shapes_iter = existing_lyon_shapes.iter_mut();
         for idx in tile.shapes.iter() {
             let el = &(**flattened_elems)[*idx];
 
-            let layer = lib_layers
-                .get(el.layer)
-                .expect("This Element's LayerKey does not exist in this Library's Layers")
-                .layernum as u8;
-
-            let color = layers.get(&layer).unwrap();
-
-            match &el.inner {
-                raw::Shape::Rect(r) => {
-                    let raw::Rect { p0, p1 } = r;
-                    let xmin = p0.x / 4;
-                    let ymin = p0.y / 4;
-                    let xmax = p1.x / 4;
-                    let ymax = p1.y / 4;
-
-                    let lyon_poly = shapes::Polygon {
-                        points: vec![
-                            (xmin as f32, ymin as f32).into(),
-                            (xmax as f32, ymin as f32).into(),
-                            (xmax as f32, ymax as f32).into(),
-                            (xmin as f32, ymax as f32).into(),
-                        ],
-                        closed: true,
-                    };
-
-                    let transform = Transform::from_translation(Vec3::new(0.0, 0.0, layer as f32));
-
-                    let lyon_shape = GeometryBuilder::build_as(
-                        &lyon_poly,
-                        DrawMode::Outlined {
-                            fill_mode: FillMode {
-                                color: *color.clone().set_a(ALPHA),
-                                options: FillOptions::default(),
-                            },
-                            outline_mode: StrokeMode {
-                                color: *color,
-                                options: StrokeOptions::default().with_line_width(WIDTH),
-                            },
-                        },
-                        transform,
-                    );
+            let layer = lib_layers.layer_id(el);
 
-                    let bundle = LyonShapeBundle {
-                        lyon: lyon_shape,
-                        marker: LyonShape,
-                    };
-
-                    if let Some((mut existing_path, mut existing_transform, mut vis)) =
-                        existing_shapes_iter.next()
-                    {
-                        *existing_path = bundle.lyon.path;
-                        *existing_transform = bundle.lyon.transform;
-                        vis.is_visible = true;
-                    } else {
-                        commands.spawn_bundle(bundle);
-                    }
+            if hidden_layers.contains(&layer) {
+                continue;
+            }
+
+            let style = layers.get(&layer).unwrap();
+
+            // patterns are laid out in world space, everything is drawn in render space
+            let outline = match world_outline(el, settings.path_ends) {
+                Ok(outline) => outline,
+                Err(e) => {
+                    warn!("skipping path {idx}: {e}");
+                    continue;
                 }
-                raw::Shape::Polygon(poly) => {
-                    let lyon_poly = shapes::Polygon {
-                        points: poly
-                            .points
-                            .iter()
-                            .map(|c| Vec2::new(c.x as f32, c.y as f32))
-                            .collect::<Vec<Vec2>>(),
-                        closed: true,
-                    };
-
-                    let transform = Transform::from_translation(Vec3::new(0.0, 0.0, layer as f32));
-
-                    let lyon_shape = GeometryBuilder::build_as(
-                        &lyon_poly,
-                        DrawMode::Outlined {
-                            fill_mode: FillMode {
-                                color: *color.clone().set_a(ALPHA),
-                                options: FillOptions::default(),
-                            },
-                            outline_mode: StrokeMode {
-                                options: StrokeOptions::default().with_line_width(WIDTH),
-                                color: *color,
-                            },
-                        },
-                        transform,
-                    );
+            };
 
-                    let bundle = LyonShapeBundle {
-                        lyon: lyon_shape,
+            let points = outline
+                .iter()
+                .map(|&p| world_to_render.point(p))
+                .collect::<Vec<Vec2>>();
+
+            // patterned and hollow shapes are drawn with a clear fill over the pattern
+            let fill_alpha = match style.fill_pattern {
+                FillPattern::Solid => settings.alpha,
+                _ => 0.0,
+            };
+            let fill = *style.fill.clone().set_a(fill_alpha);
+
+            if let raw::Shape::Rect(_) = el.inner {
+                rects.extend(outlined_rect(
+                    points[0].min(points[2]),
+                    points[0].max(points[2]),
+                    style.z,
+                    fill,
+                    style.frame,
+                    settings.width,
+                ));
+            } else {
+                spawn_or_reuse(
+                    LyonShapeBundle {
+                        lyon: outlined_polygon(points, style.z, fill, style.frame, settings.width),
                         marker: LyonShape,
-                    };
-
-                    if let Some((mut existing_path, mut existing_transform, mut vis)) =
-                        existing_shapes_iter.next()
-                    {
-                        *existing_path = bundle.lyon.path;
-                        *existing_transform = bundle.lyon.transform;
-                        vis.is_visible = true;
-                    } else {
-                        commands.spawn_bundle(bundle);
-                    }
-                }
-                raw::Shape::Path(path) => {
-                    let poly = make_path_into_polygon(path);
-                    let lyon_poly = shapes::Polygon {
-                        points: poly
-                            .exterior()
-                            .points()
-                            .map(|c| Vec2::new(c.x() as f32, c.y() as f32))
-                            .collect::<Vec<Vec2>>(),
-                        closed: true,
-                    };
-
-                    let transform = Transform::from_translation(Vec3::new(0.0, 0.0, layer as f32));
-
-                    let lyon_shape = GeometryBuilder::build_as(
-                        &lyon_poly,
-                        DrawMode::Outlined {
-                            fill_mode: FillMode {
-                                color: *color.clone().set_a(ALPHA),
-                                options: FillOptions::default(),
-                            },
-                            outline_mode: StrokeMode {
-                                options: StrokeOptions::default().with_line_width(WIDTH),
-                                color: *color,
-                            },
-                        },
-                        transform,
-                    );
+                    },
+                    render_layer,
+                    Some(*idx),
+                    tessellation_cache.get(*idx),
+                );
+            }
 
-                    let bundle = LyonShapeBundle {
-                        lyon: lyon_shape,
+            if let Some(pattern) =
+                fill_pattern_shape(&outline, style, &tile.extents, &world_to_render, &settings)
+            {
+                spawn_or_reuse(
+                    LyonShapeBundle {
+                        lyon: pattern,
                         marker: LyonShape,
-                    };
-
-                    if let Some((mut existing_path, mut existing_transform, mut vis)) =
-                        existing_shapes_iter.next()
-                    {
-                        *existing_path = bundle.lyon.path;
-                        *existing_transform = bundle.lyon.transform;
-                        vis.is_visible = true;
-                    } else {
-                        commands.spawn_bundle(bundle);
-                    }
-                }
+                    },
+                    render_layer,
+                    None,
+                    None,
+                );
+            }
+        }
+
+        let rects = RectInstances::new(rects);
+
+        match rect_batches
+            .iter_mut()
+            .find(|(batch_slot, ..)| *batch_slot == slot)
+        {
+            Some((_, mut batch, mut vis)) => {
+                *batch = rects;
+                vis.is_visible = true;
+            }
+            None => new_rect_batches.push((*slot, rects)),
+        }
+    }
+
+    for (slot, rects) in new_rect_batches {
+        commands
+            .spawn_bundle(SpatialBundle::default())
+            .insert(rects)
+            .insert(slot.shape_layer())
+            .insert(slot);
+    }
+}
+
+/// Fill patterns are drawn just under the other shapes of their layer, far less than the
+/// distance between layers in `0.0..LAYER_Z_RANGE`
+const PATTERN_Z_OFFSET: f32 = 0.01;
+
+/// Labels are drawn above every layer
+const LABEL_Z: f32 = 900.0;
+
+/// Largest font size glyphs are rasterized at, larger labels are scaled up instead
+const MAX_LABEL_FONT_PX: f32 = 128.0;
+
+fn spawn_labels_system(
+    mut commands: Commands,
+    tiles: RenderTiles,
+    flattened_labels: Res<FlattenedLabels>,
+    label_font: Res<LabelFont>,
+    settings: Res<TiledRendererSettings>,
+    world_to_render: Res<WorldToRender>,
+    mut draw_ev: EventReader<DrawTileEvent>,
+    mut existing_labels: Query<
+        (
+            &mut Text,
+            &mut Transform,
+            &mut Visibility,
+            &mut RenderLayers,
+        ),
+        With<TileLabel>,
+    >,
+) {
+    let font = match &label_font.0 {
+        Some(font) => font,
+        None => return,
+    };
+
+    let mut existing_labels_iter = existing_labels.iter_mut();
+
+    for DrawTileEvent { tile: key, slot } in draw_ev.iter() {
+        // spawn_cameras_system warns about tiles dropped before they were drawn
+        let tile = match tiles.get(key) {
+            Some(tile) => tile,
+            None => continue,
+        };
+        let world_to_render = world_to_render.at_tile(&tile.extents);
+
+        // tiles higher up the pyramid and of the live view are smaller, so their labels come out
+        // larger
+        let tile_size = tile.extents.width() as u64;
+
+        if tile.labels.is_empty() || settings.label_px(tile_size) < settings.min_label_px {
+            continue;
+        }
+
+        // glyphs are rasterized at their size in the hi-res texture and scaled back to render
+        // units
+        let hires_px_per_unit = settings.texture_dim() as f32 / tile_size as f32;
+        let font_size = (settings.label_height as f32 * hires_px_per_unit).min(MAX_LABEL_FONT_PX);
+        let scale = world_to_render.length(settings.label_height as f64) / font_size;
+
+        let style = TextStyle {
+            font: font.clone(),
+            font_size,
+            color: Color::WHITE,
+        };
+
+        let alignment = TextAlignment {
+            vertical: VerticalAlign::Bottom,
+            horizontal: HorizontalAlign::Left,
+        };
+
+        for idx in tile.labels.iter() {
+            let label = &(**flattened_labels)[*idx];
+
+            let text =
+                Text::from_section(label.string.clone(), style.clone()).with_alignment(alignment);
+
+            let loc = world_to_render.point((label.loc.x as f64, label.loc.y as f64));
+            let transform = Transform::from_translation(loc.extend(LABEL_Z))
+                .with_scale(Vec3::new(scale, scale, 1.0));
+
+            if let Some((mut existing_text, mut existing_transform, mut vis, mut layer)) =
+                existing_labels_iter.next()
+            {
+                *existing_text = text;
+                *existing_transform = transform;
+                *layer = slot.shape_layer();
+                vis.is_visible = true;
+            } else {
+                commands
+                    .spawn_bundle(Text2dBundle {
+                        text,
+                        transform,
+                        ..default()
+                    })
+                    .insert(slot.shape_layer())
+                    .insert(TileLabel);
             }
         }
     }
@@ -257,114 +490,141 @@ fn spawn_shapes_system(
 
 fn spawn_cameras_system(
     mut commands: Commands,
-    hires_image: Res<HiResHandle>,
-    mut mesh_and_material: Local<Option<(Handle<Mesh>, Handle<PostProcessingMaterial>)>>,
+    hires_images: Res<HiResHandles>,
+    mut mesh: Local<Option<Handle<Mesh>>>,
+    mut downscaling_quads: Local<HashSet<RenderSlot>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut post_processing_materials: ResMut<Assets<PostProcessingMaterial>>,
     render_queue: Res<RenderQueue>,
-    tilemap: Res<Tilemap>,
-    lower_left_res: Res<TilemapLowerLeft>,
+    tiles: RenderTiles,
+    world_to_render: Res<WorldToRender>,
+    settings: Res<TiledRendererSettings>,
     mut draw_ev: EventReader<DrawTileEvent>,
     rendering_done_channel: Res<RenderingDoneChannel>,
-    mut rendering_complete_ev: EventWriter<RenderingCompleteEvent>,
-    mut hires_cam_q: Query<(&mut Camera, &mut Transform), With<HiResCam>>,
-    mut accumulation_cam_q: Query<&mut Camera, (With<AccumulationCam>, Without<HiResCam>)>,
+    mut hires_cam_q: Query<
+        (
+            &RenderSlot,
+            &mut Camera,
+            &mut Transform,
+            &mut OrthographicProjection,
+        ),
+        With<HiResCam>,
+    >,
+    mut accumulation_cam_q: Query<
+        (&RenderSlot, &mut Camera, &mut OrthographicProjection),
+        (With<AccumulationCam>, Without<HiResCam>),
+    >,
 ) {
-    for DrawTileEvent(key) in draw_ev.iter() {
-        // if key.0 % 15 == 0 {
-        //     rendering_complete_ev.send_default();
-        //     continue;
-        // }
+    let mut batch = vec![];
 
-        let tile = tilemap.get(key).unwrap();
+    for DrawTileEvent { tile: key, slot } in draw_ev.iter() {
+        // skipped tiles still complete with the rest of the batch, so the walk moves past them
+        batch.push(*key);
 
-        let tile_x = tile.extents.min().x - lower_left_res.x;
-        let tile_y = tile.extents.min().y - lower_left_res.y;
-
-        assert!(tile_x >= 0, "tile_x should be positive");
-        assert!(tile_y >= 0, "tile_y should be positive");
-        let x = tile_x;
-        let y = tile_y;
+        let (tile, (target, physical_position)) = match (tiles.get(key), tiles.target(key)) {
+            (Some(tile), Some(target)) => (tile, target),
+            _ => {
+                warn!("skipping {key:?}, its tile was dropped before it was drawn");
+                continue;
+            }
+        };
 
-        let transform = Transform::from_translation(Vec3::new(x as f32, y as f32, 999.0));
+        // the tile's shapes and labels are drawn in a render space starting at its corner
+        let world_to_render = world_to_render.at_tile(&tile.extents);
+        let transform = Transform::from_translation(
+            world_to_render.camera_position(&tile.extents).extend(999.0),
+        );
 
-        for (mut cam, mut cam_transform) in hires_cam_q.iter_mut() {
+        for (_, mut cam, mut cam_transform, mut projection) in hires_cam_q
+            .iter_mut()
+            .filter(|(cam_slot, ..)| *cam_slot == slot)
+        {
             cam.is_active = true;
             *cam_transform = transform;
+            // tiles higher up the pyramid and those of the live view fit less of the world into
+            // the same texture
+            projection.scale = world_to_render.camera_scale(&tile.extents, settings.texture_dim());
         }
 
-        let (mesh_handle, material_handle) =
-            if let Some((mesh_handle, material_handle)) = mesh_and_material.as_ref() {
-                ((*mesh_handle).clone(), (*material_handle).clone())
-            } else {
-                let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-                mesh.insert_attribute(
-                    Mesh::ATTRIBUTE_POSITION,
-                    vec![[-1.0, 1.0, 0.0], [-1.0, -3.0, 0.0], [3.0, 1.0, 0.0]],
-                );
-
-                mesh.insert_attribute(
-                    Mesh::ATTRIBUTE_UV_0,
-                    vec![[0.0, 0.0], [0.0, 2.0], [2.0, 0.0]],
-                );
-
-                mesh.insert_attribute(
-                    Mesh::ATTRIBUTE_NORMAL,
-                    vec![[0.0, 0.0, 1.0], [0.0, 0.0, 1.0], [0.0, 0.0, 1.0]],
-                );
-
-                let mesh_handle = meshes.add(mesh);
+        // the downscaling quads live as long as the app, one per slot
+        if downscaling_quads.insert(*slot) {
+            let mesh_handle = mesh
+                .get_or_insert_with(|| {
+                    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+                    mesh.insert_attribute(
+                        Mesh::ATTRIBUTE_POSITION,
+                        vec![[-1.0, 1.0, 0.0], [-1.0, -3.0, 0.0], [3.0, 1.0, 0.0]],
+                    );
 
-                // This material has the texture that has been rendered.
-                let material_handle = post_processing_materials.add(PostProcessingMaterial {
-                    source_image: hires_image.clone(),
-                });
+                    mesh.insert_attribute(
+                        Mesh::ATTRIBUTE_UV_0,
+                        vec![[0.0, 0.0], [0.0, 2.0], [2.0, 0.0]],
+                    );
 
-                *mesh_and_material = Some((mesh_handle.clone(), material_handle.clone()));
+                    mesh.insert_attribute(
+                        Mesh::ATTRIBUTE_NORMAL,
+                        vec![[0.0, 0.0, 1.0], [0.0, 0.0, 1.0], [0.0, 0.0, 1.0]],
+                    );
 
-                (mesh_handle, material_handle)
-            };
+                    meshes.add(mesh)
+                })
+                .clone();
 
-        // Post processing 2d quad, with material using the render texture done by the main camera, with a custom shader.
-        commands
-            .spawn_bundle(MaterialMesh2dBundle {
-                mesh: mesh_handle.into(),
-                material: material_handle,
-                ..default()
-            })
-            .insert(DOWNSCALING_PASS_LAYER);
+            // This material has the texture that has been rendered by the slot's hi-res camera.
+            let material_handle = post_processing_materials.add(PostProcessingMaterial {
+                source_image: hires_images[slot.0].clone(),
+            });
 
-        let physical_position = UVec2::new(
-            (x / TILE_SIZE_IN_PX as i64) as u32,
-            (y / TILE_SIZE_IN_PX as i64) as u32,
-        );
+            // Post processing 2d quad, with material using the render texture done by the main camera, with a custom shader.
+            commands
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: mesh_handle.into(),
+                    material: material_handle,
+                    ..default()
+                })
+                .insert(slot.downscaling_layer());
+        }
 
         info!("viewport: {physical_position:?}");
 
-        for mut cam in accumulation_cam_q.iter_mut() {
+        for (_, mut cam, mut projection) in accumulation_cam_q
+            .iter_mut()
+            .filter(|(cam_slot, ..)| *cam_slot == slot)
+        {
             cam.is_active = true;
+            if cam.target != RenderTarget::Image(target.clone()) {
+                cam.target = RenderTarget::Image(target.clone());
+                // the camera only picks up the size of a new target along with a new projection
+                projection.set_changed();
+            }
             cam.viewport = Some(Viewport {
                 physical_position,
-                physical_size: UVec2::new(TILE_SIZE_IN_PX, TILE_SIZE_IN_PX),
+                physical_size: UVec2::new(settings.tile_size_in_px, settings.tile_size_in_px),
                 ..default()
             });
         }
+    }
 
-        let s = rendering_done_channel.sender.clone();
+    if batch.is_empty() {
+        return;
+    }
 
-        let key = key.clone();
+    // every tile of the batch is drawn in the same frame, so one callback covers all of them
+    let s = rendering_done_channel.sender.clone();
 
-        render_queue.on_submitted_work_done(move || {
-            s.send(()).unwrap();
-            info!("work done event sent for tile {key:?}!");
-        });
-    }
+    render_queue.on_submitted_work_done(move || {
+        s.send(()).unwrap();
+        info!("work done event sent for tiles {batch:?}!");
+    });
 }
 
 fn despawn_system(
     mut hires_cam_q: Query<&mut Camera, With<HiResCam>>,
     mut accumulation_cam_q: Query<&mut Camera, (With<AccumulationCam>, Without<HiResCam>)>,
-    mut shape_q: Query<&mut Visibility, With<LyonShape>>,
+    mut shape_q: Query<
+        &mut Visibility,
+        Or<(With<LyonShape>, With<TileLabel>, With<RectInstances>)>,
+    >,
     rendering_done_channel: Res<RenderingDoneChannel>,
     mut rendering_complete_ev: EventWriter<RenderingCompleteEvent>,
 ) {
@@ -386,6 +646,90 @@ fn despawn_system(
     }
 }
 
+/// The lyon shape of a closed outline drawn with `fill` and a `width` wide `frame`. It is
+/// tessellated relative to its first point, so that the mesh can be cached and drawn in any tile.
+pub fn outlined_polygon(
+    points: Vec<Vec2>,
+    z: f32,
+    fill: Color,
+    frame: Color,
+    width: f32,
+) -> ShapeBundle {
+    let anchor = points.first().copied().unwrap_or_default();
+
+    let lyon_poly = shapes::Polygon {
+        points: points.into_iter().map(|p| p - anchor).collect(),
+        closed: true,
+    };
+
+    GeometryBuilder::build_as(
+        &lyon_poly,
+        DrawMode::Outlined {
+            fill_mode: FillMode {
+                color: fill,
+                options: FillOptions::default(),
+            },
+            outline_mode: StrokeMode {
+                color: frame,
+                options: StrokeOptions::default().with_line_width(width),
+            },
+        },
+        Transform::from_translation(anchor.extend(z)),
+    )
+}
+
+/// The lyon shape of the fill pattern of `style` inside the world space `outline`, for the tile
+/// covering `extents`, or `None` if the pattern has no lines or dots there
+pub fn fill_pattern_shape(
+    outline: &[(f64, f64)],
+    style: &LayerStyle,
+    extents: &GeoRect,
+    world_to_render: &WorldToRender,
+    settings: &TiledRendererSettings,
+) -> Option<ShapeBundle> {
+    // patterns keep their spacing in pixels at every level of the pyramid
+    let pattern_spacing = settings.pattern_spacing(extents.width() as f64);
+    let pattern_width = world_to_render.length(FillPattern::line_width(pattern_spacing));
+
+    let mut pattern = GeometryBuilder::new();
+    let mut pattern_is_empty = true;
+
+    for &diagonal in style.fill_pattern.diagonals() {
+        for (a, b) in hatch_segments(outline, pattern_spacing, diagonal, extents) {
+            pattern = pattern.add(&shapes::Line(
+                world_to_render.point(a),
+                world_to_render.point(b),
+            ));
+            pattern_is_empty = false;
+        }
+    }
+
+    if style.fill_pattern == FillPattern::Dots {
+        for (x, y) in dot_centers(outline, pattern_spacing, extents) {
+            pattern = pattern.add(&shapes::Rectangle {
+                extents: Vec2::splat(pattern_width),
+                origin: RectangleOrigin::CustomCenter(world_to_render.point((x, y))),
+            });
+            pattern_is_empty = false;
+        }
+    }
+
+    if pattern_is_empty {
+        return None;
+    }
+
+    let mode = match style.fill_pattern {
+        FillPattern::Dots => DrawMode::Fill(FillMode::color(style.fill)),
+        _ => DrawMode::Stroke(StrokeMode::new(style.fill, pattern_width)),
+    };
+
+    // patterns go under the frames, which the shapes' outlines and rectangle instances at the
+    // same z would otherwise cover or not depending on draw order
+    let transform = Transform::from_translation(Vec3::new(0.0, 0.0, style.z - PATTERN_Z_OFFSET));
+
+    Some(pattern.build(mode, transform))
+}
+
 #[derive(AsBindGroup, TypeUuid, Clone)]
 #[uuid = "bc2f08eb-a0fb-43f1-a908-54871ea597d5"]
 struct PostProcessingMaterial {
@@ -403,3 +747,243 @@ impl Material2d for PostProcessingMaterial {
         "shaders/downscale.wgsl".into()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{rect_instancing::RectInstance, tile_pyramid::child_extents};
+
+    fn elem(inner: raw::Shape) -> raw::Element {
+        raw::Element {
+            net: None,
+            layer: raw::LayerKey::default(),
+            purpose: raw::LayerPurpose::Drawing,
+            inner,
+        }
+    }
+
+    /// Whether `p` is inside or on the edges of triangle `[a, b, c]`, with either winding
+    pub(crate) fn triangle_covers([a, b, c]: [Vec2; 3], p: Vec2) -> bool {
+        if (b - a).perp_dot(c - a) == 0.0 {
+            return false;
+        }
+        let sides = [(a, b), (b, c), (c, a)].map(|(a, b)| (b - a).perp_dot(p - a));
+        sides.iter().all(|&d| d >= 0.0) || sides.iter().all(|&d| d <= 0.0)
+    }
+
+    /// The hi-res texture of `extents` with `layers` of triangles drawn over each other, each
+    /// pixel the index of the last layer covering its center plus one, or 0
+    fn rasterize(
+        layers: &[Vec<[Vec2; 3]>],
+        world_to_render: &WorldToRender,
+        extents: &GeoRect,
+        texture_dim: u32,
+    ) -> Vec<u8> {
+        let corner = world_to_render.camera_position(extents);
+        let scale = world_to_render.camera_scale(extents, texture_dim);
+
+        let mut pixels = vec![0; (texture_dim * texture_dim) as usize];
+
+        for py in 0..texture_dim {
+            for px in 0..texture_dim {
+                let p = corner + (Vec2::new(px as f32, py as f32) + 0.5) * scale;
+
+                for (i, triangles) in layers.iter().enumerate() {
+                    if triangles.iter().any(|&t| triangle_covers(t, p)) {
+                        pixels[(py * texture_dim + px) as usize] = i as u8 + 1;
+                    }
+                }
+            }
+        }
+
+        pixels
+    }
+
+    /// The triangles of the quad each instance draws
+    fn instance_triangles(instances: &[RectInstance]) -> Vec<[Vec2; 3]> {
+        instances
+            .iter()
+            .flat_map(|instance| {
+                let min = instance.position.truncate();
+                let max = min + instance.size;
+                let (a, b, c, d) = (min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y));
+                [[a, b, c], [a, c, d]]
+            })
+            .collect()
+    }
+
+    /// The triangles lyon tessellates for `shape`, in the space of its transform's parent, with
+    /// the colour they are drawn in, the fill before the outline of outlined shapes
+    pub(crate) fn lyon_triangles(shape: &ShapeBundle) -> Vec<(Vec<[Vec2; 3]>, Color)> {
+        let offset = shape.transform.translation.truncate();
+        let triangles = |buffers: tess::VertexBuffers<Vec2, u32>| {
+            buffers
+                .indices
+                .chunks(3)
+                .map(|t| [0, 1, 2].map(|i| buffers.vertices[t[i] as usize] + offset))
+                .collect::<Vec<[Vec2; 3]>>()
+        };
+
+        let fill = |mode: &FillMode| {
+            let mut buffers = tess::VertexBuffers::new();
+            tess::FillTessellator::new()
+                .tessellate_path(
+                    &shape.path.0,
+                    &mode.options,
+                    &mut tess::BuffersBuilder::new(&mut buffers, |v: tess::FillVertex| {
+                        Vec2::new(v.position().x, v.position().y)
+                    }),
+                )
+                .unwrap();
+            (triangles(buffers), mode.color)
+        };
+
+        let stroke = |mode: &StrokeMode| {
+            let mut buffers = tess::VertexBuffers::new();
+            tess::StrokeTessellator::new()
+                .tessellate_path(
+                    &shape.path.0,
+                    &mode.options,
+                    &mut tess::BuffersBuilder::new(&mut buffers, |v: tess::StrokeVertex| {
+                        Vec2::new(v.position().x, v.position().y)
+                    }),
+                )
+                .unwrap();
+            (triangles(buffers), mode.color)
+        };
+
+        match &shape.mode {
+            DrawMode::Fill(fill_mode) => vec![fill(fill_mode)],
+            DrawMode::Stroke(stroke_mode) => vec![stroke(stroke_mode)],
+            DrawMode::Outlined {
+                fill_mode,
+                outline_mode,
+            } => vec![fill(fill_mode), stroke(outline_mode)],
+        }
+    }
+
+    #[test]
+    fn rect_and_polygon_cover_the_same_pixels() {
+        // one render unit per database unit, and edges of the even frame width on whole units,
+        // so that no pixel center of the two levels below lies on the outer edge of a shape
+        let settings = TiledRendererSettings {
+            num_tiles: 4,
+            tile_size_in_px: 16,
+            width: 2.0,
+            ..default()
+        };
+
+        let grid = TileGrid {
+            origin: (-128, -128),
+            tile_size: 64,
+            num_x: 2,
+            num_y: 2,
+        };
+
+        let (p0, p1) = (raw::Point::new(-120, -110), raw::Point::new(-90, -75));
+
+        let rect = elem(raw::Shape::Rect(raw::Rect { p0, p1 }));
+        let polygon = elem(raw::Shape::Polygon(raw::Polygon {
+            points: vec![
+                p0,
+                raw::Point::new(p1.x, p0.y),
+                p1,
+                raw::Point::new(p0.x, p1.y),
+            ],
+        }));
+
+        // the pixels of the hi-res texture of tile (0, 0) and of its lower left child a level up
+        for extents in [
+            grid.tile_extents(0, 0),
+            child_extents(&grid.tile_extents(0, 0), (0, 0)),
+        ] {
+            // as spawn_shapes_system draws them, the rect as instances of a quad, the polygon as
+            // a lyon shape
+            let world_to_render =
+                WorldToRender::new(&grid, settings.texture_dim()).at_tile(&extents);
+            let to_render = |el: &raw::Element| {
+                world_outline(el, PathEnds::Flush)
+                    .unwrap()
+                    .into_iter()
+                    .map(|p| world_to_render.point(p))
+                    .collect::<Vec<Vec2>>()
+            };
+
+            let points = to_render(&rect);
+            let instances = outlined_rect(
+                points[0].min(points[2]),
+                points[0].max(points[2]),
+                0.0,
+                Color::WHITE,
+                Color::BLACK,
+                settings.width,
+            );
+            let rect_pixels = rasterize(
+                &[
+                    instance_triangles(&instances[..1]),
+                    instance_triangles(&instances[1..]),
+                ],
+                &world_to_render,
+                &extents,
+                settings.texture_dim(),
+            );
+
+            let shape = outlined_polygon(
+                to_render(&polygon),
+                0.0,
+                Color::WHITE,
+                Color::BLACK,
+                settings.width,
+            );
+            let polygon_pixels = rasterize(
+                &lyon_triangles(&shape)
+                    .into_iter()
+                    .map(|(triangles, _)| triangles)
+                    .collect::<Vec<Vec<[Vec2; 3]>>>(),
+                &world_to_render,
+                &extents,
+                settings.texture_dim(),
+            );
+
+            // both the fill and the frame show
+            assert!(rect_pixels.contains(&1) && rect_pixels.contains(&2));
+            assert!(
+                rect_pixels == polygon_pixels,
+                "the rect and the polygon differ in {extents:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_of_the_grid_fill_the_hi_res_texture() {
+        let grid = TileGrid {
+            origin: (-200, -200),
+            tile_size: 100,
+            num_x: 2,
+            num_y: 2,
+        };
+
+        let world_to_render = WorldToRender::new(&grid, 64);
+
+        let extents = grid.tile_extents(1, 1);
+        assert_eq!(
+            world_to_render.camera_position(&extents),
+            Vec2::new(64.0, 64.0)
+        );
+
+        // each tile is drawn with the render space origin at its lower left corner
+        let tile_render = world_to_render.at_tile(&extents);
+        assert_eq!(tile_render.camera_position(&extents), Vec2::ZERO);
+        assert_eq!(tile_render.point((-50.0, 0.0)), Vec2::new(32.0, 64.0));
+        assert_eq!(tile_render.units_per_px, world_to_render.units_per_px);
+
+        assert_eq!(world_to_render.camera_scale(&extents, 64), 1.0);
+        assert_eq!(
+            world_to_render.camera_scale(&child_extents(&extents, (1, 0)), 64),
+            0.5
+        );
+
+        // database units per hi-res pixel
+        assert_eq!(world_to_render.length(world_to_render.units_per_px), 1.0);
+    }
+}
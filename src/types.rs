@@ -1,14 +1,21 @@
 use bevy::{
-    prelude::{Bundle, Color, Component, Deref, DerefMut, Handle, Image},
+    prelude::{
+        Bundle, Color, Component, Deref, DerefMut, Entity, Handle, Image, OrthographicProjection,
+        SystemLabel, Transform, Vec2,
+    },
     render::view::RenderLayers,
     tasks::Task,
-    utils::HashMap,
+    text::Font,
+    utils::{HashMap, HashSet},
 };
 
 use crossbeam_channel::{Receiver, Sender};
+use geo::Intersects;
 use layout21::raw::{self, Library};
 
-use std::ops::Range;
+use crate::{fill_pattern::FillPattern, import::LibraryOpenError, path_to_poly::PathEnds};
+
+use std::{collections::VecDeque, fmt, ops::RangeInclusive, str::FromStr};
 
 //
 // constants
@@ -20,12 +27,9 @@ pub const MAIN_CAMERA_PRIORITY: isize = 2;
 pub const DOWNSCALING_PASS_LAYER: RenderLayers = RenderLayers::layer(1);
 pub const MAIN_CAMERA_LAYER: RenderLayers = RenderLayers::layer(2);
 
-pub const ALPHA: f32 = 0.1;
-pub const WIDTH: f32 = 10.0;
-
-pub const NUM_TILES: u32 = 64;
-pub const TILE_SIZE_IN_PX: u32 = 64;
-pub const TEXTURE_DIM: u32 = NUM_TILES * TILE_SIZE_IN_PX;
+/// Most tiles rendered in one frame. Every render slot after the first takes two of the 32
+/// render layers, one for its shapes and one for its downscaling pass.
+pub const MAX_BATCH_SIZE: usize = 8;
 
 #[derive(Debug, Eq, PartialEq, Default, Clone, Copy)]
 pub struct Point {
@@ -66,43 +70,591 @@ impl Rect {
 //
 
 pub type GeoRect = geo::Rect<i64>;
-pub type GeoPolygon = geo::Polygon<i64>;
+/// Polygons are kept in floating point so that path outlines, whose vertices can fall on the half
+/// database unit grid, are represented exactly
+pub type GeoPolygon = geo::Polygon<f64>;
 
 #[derive(Debug)]
 pub struct Tile {
     pub extents: GeoRect,
     pub shapes: Vec<usize>,
+    /// Indices into `FlattenedLabels` of the labels anchored in this tile
+    pub labels: Vec<usize>,
+}
+
+impl Tile {
+    /// Whether rendering the tile would draw nothing, so the render walk can skip it. Labels
+    /// only count when they are drawn.
+    pub fn is_empty(&self, draw_labels: bool) -> bool {
+        self.shapes.is_empty() && !(draw_labels && !self.labels.is_empty())
+    }
 }
 
 #[derive(Debug, Default, Deref, DerefMut)]
 pub struct Tilemap(pub HashMap<(u32, u32), Tile>);
 
-#[derive(Debug, Default)]
-pub struct TilemapLowerLeft {
-    pub x: i64,
-    pub y: i64,
+/// Maps world space, in database units, to the space shapes are drawn in and the hi-res cameras
+/// look at. One render unit is one hi-res texture pixel of a level 0 tile. Each tile is drawn in
+/// the render space of `at_tile`, starting at its own lower left corner, so that coordinates
+/// stay within a few texture widths of zero and keep their precision as f32 anywhere in the grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldToRender {
+    /// World space point at the render space origin
+    pub origin: (i64, i64),
+    /// Database units per render unit
+    pub units_per_px: f64,
+}
+
+impl Default for WorldToRender {
+    fn default() -> Self {
+        Self {
+            origin: (0, 0),
+            units_per_px: 1.0,
+        }
+    }
+}
+
+impl WorldToRender {
+    /// Render space of `grid` with tiles rendered into `texture_dim` wide hi-res textures
+    pub fn new(grid: &TileGrid, texture_dim: u32) -> Self {
+        Self {
+            origin: grid.origin,
+            units_per_px: grid.tile_size as f64 / texture_dim as f64,
+        }
+    }
+
+    /// The same mapping with its origin at the lower left corner of `extents`, the render space
+    /// a tile's shapes, labels and hi-res camera are placed in
+    pub fn at_tile(&self, extents: &GeoRect) -> Self {
+        let min = extents.min();
+        Self {
+            origin: (min.x, min.y),
+            ..*self
+        }
+    }
+
+    /// Render units per database unit
+    pub fn scale(&self) -> f64 {
+        1.0 / self.units_per_px
+    }
+
+    pub fn point(&self, (x, y): (f64, f64)) -> Vec2 {
+        Vec2::new(
+            ((x - self.origin.0 as f64) * self.scale()) as f32,
+            ((y - self.origin.1 as f64) * self.scale()) as f32,
+        )
+    }
+
+    pub fn length(&self, length: f64) -> f32 {
+        (length * self.scale()) as f32
+    }
+
+    /// Where a hi-res camera rendering `extents` is placed, its bottom left corner, the origin in
+    /// the render space of `at_tile`
+    pub fn camera_position(&self, extents: &GeoRect) -> Vec2 {
+        let min = extents.min();
+        self.point((min.x as f64, min.y as f64))
+    }
+
+    /// Projection scale of a hi-res camera rendering `extents` into its `texture_dim` wide
+    /// texture, 1 for the tiles of the grid and smaller for those higher up the pyramid and of
+    /// the live view
+    pub fn camera_scale(&self, extents: &GeoRect, texture_dim: u32) -> f32 {
+        self.length(extents.width() as f64) / texture_dim as f32
+    }
+}
+
+/// World space layout of the tilemap, shared by tilemap construction and shape binning so that
+/// the two always agree on which world space region a tile index covers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TileGrid {
+    /// World space lower left corner of tile (0, 0)
+    pub origin: (i64, i64),
+    /// World space side length of a (square) tile
+    pub tile_size: u64,
+    /// Number of tiles along x
+    pub num_x: u32,
+    /// Number of tiles along y
+    pub num_y: u32,
 }
 
+impl TileGrid {
+    /// A grid covering `bbox` with `num_tiles` tiles along its longer side, and only as many
+    /// tiles along the shorter side as are needed to cover it
+    pub fn from_bbox(bbox: &raw::BoundBox, num_tiles: u32) -> Self {
+        let dx = (bbox.p1.x - bbox.p0.x) as u64;
+        let dy = (bbox.p1.y - bbox.p0.y) as u64;
+
+        let max_side_length = dx.max(dy);
+        let tile_size = ((max_side_length + num_tiles as u64 - 1) / num_tiles as u64).max(1);
+
+        let tiles_along =
+            |side: u64| ((side + tile_size - 1) / tile_size).clamp(1, num_tiles as u64);
+
+        Self {
+            origin: (bbox.p0.x as i64, bbox.p0.y as i64),
+            tile_size,
+            num_x: tiles_along(dx) as u32,
+            num_y: tiles_along(dy) as u32,
+        }
+    }
+
+    /// Size in pixels of an accumulation texture holding every tile of this grid
+    pub fn texture_size(&self, tile_size_in_px: u32) -> (u32, u32) {
+        (self.num_x * tile_size_in_px, self.num_y * tile_size_in_px)
+    }
+
+    /// Pixel position of the top left corner of tile (`ix`, `iy`) in the accumulation texture,
+    /// whose first row is the top of the design
+    pub fn texture_position(&self, ix: u32, iy: u32, tile_size_in_px: u32) -> (u32, u32) {
+        (
+            ix * tile_size_in_px,
+            (self.num_y - 1 - iy) * tile_size_in_px,
+        )
+    }
+
+    /// Position of the world space point `p` in the main view, which shows the accumulation
+    /// texture with its bottom left corner at the origin
+    pub fn world_to_view(&self, p: (i64, i64), tile_size_in_px: u32) -> (f32, f32) {
+        let px_per_unit = tile_size_in_px as f32 / self.tile_size as f32;
+        (
+            (p.0 - self.origin.0) as f32 * px_per_unit,
+            (p.1 - self.origin.1) as f32 * px_per_unit,
+        )
+    }
+
+    /// World space point at position `p` in the main view, the inverse of `world_to_view`
+    pub fn view_to_world(&self, p: (f32, f32), tile_size_in_px: u32) -> (i64, i64) {
+        let units_per_px = self.tile_size as f32 / tile_size_in_px as f32;
+        (
+            self.origin.0 + (p.0 * units_per_px).floor() as i64,
+            self.origin.1 + (p.1 * units_per_px).floor() as i64,
+        )
+    }
+
+    /// World space extents of tile (`ix`, `iy`)
+    pub fn tile_extents(&self, ix: u32, iy: u32) -> GeoRect {
+        let tile_size = self.tile_size as i64;
+        let xmin = self.origin.0 + ix as i64 * tile_size;
+        let ymin = self.origin.1 + iy as i64 * tile_size;
+        GeoRect::new((xmin, ymin), (xmin + tile_size, ymin + tile_size))
+    }
+
+    /// Index of the tile containing world space coordinate `v` along one axis, clamped to the
+    /// grid so coordinates outside of it map to the nearest edge tile
+    fn axis_index(&self, v: i64, origin: i64, num: u32) -> u32 {
+        (v - origin)
+            .div_euclid(self.tile_size as i64)
+            .clamp(0, num as i64 - 1) as u32
+    }
+
+    /// Inclusive ranges of tile indices along x and y that the world space box (`p0`, `p1`)
+    /// touches, clamped to the grid
+    pub fn tile_range(
+        &self,
+        p0: (i64, i64),
+        p1: (i64, i64),
+    ) -> (RangeInclusive<u32>, RangeInclusive<u32>) {
+        let min_x = self.axis_index(p0.0, self.origin.0, self.num_x);
+        let max_x = self.axis_index(p1.0, self.origin.0, self.num_x);
+        let min_y = self.axis_index(p0.1, self.origin.1, self.num_y);
+        let max_y = self.axis_index(p1.1, self.origin.1, self.num_y);
+
+        (min_x..=max_x, min_y..=max_y)
+    }
+
+    /// An empty tilemap with one tile per grid cell
+    pub fn build_tilemap(&self) -> Tilemap {
+        let mut tilemap = Tilemap::default();
+
+        for iy in 0..self.num_y {
+            for ix in 0..self.num_x {
+                tilemap.insert(
+                    (ix, iy),
+                    Tile {
+                        extents: self.tile_extents(ix, iy),
+                        shapes: vec![],
+                        labels: vec![],
+                    },
+                );
+            }
+        }
+
+        tilemap
+    }
+}
+
+/// The tiles left to render in the current walk, only ever containing non-empty tiles
 #[derive(Debug, Default, Deref, DerefMut)]
-pub struct TileIndexIter(pub Option<itertools::Product<Range<u32>, Range<u32>>>);
+pub struct TileIndexIter(pub Option<std::vec::IntoIter<(u32, u32)>>);
+
+/// Region of the main view a camera shows. The main view has the accumulation texture's bottom
+/// left corner at the origin and one unit per pixel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewRect {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl ViewRect {
+    /// What a camera with its origin at the bottom left of the window shows
+    pub fn from_camera(transform: &Transform, projection: &OrthographicProjection) -> Self {
+        let t = transform.translation.truncate();
+        Self {
+            min: t + Vec2::new(projection.left, projection.bottom) * projection.scale,
+            max: t + Vec2::new(projection.right, projection.top) * projection.scale,
+        }
+    }
+
+    pub fn center(&self) -> Vec2 {
+        (self.min + self.max) / 2.0
+    }
+}
+
+/// What the main camera shows, `None` until there is one or when running headless
+#[derive(Debug, Default, Clone, Copy, Deref, DerefMut)]
+pub struct MainView(pub Option<ViewRect>);
+
+impl MainView {
+    /// Order `tiles` for rendering, those in view first and closest to its centre first, then
+    /// the rest by their distance to it. Without a view tiles are rendered row major from the
+    /// bottom left.
+    pub fn order_tiles(&self, tiles: &mut [(u32, u32)], tile_size_in_px: u32) {
+        tiles.sort_by_key(|&(x, y)| (y, x));
+
+        let view = match self.0 {
+            Some(view) => view,
+            None => return,
+        };
+
+        let size = tile_size_in_px as f32;
+        let center = view.center();
+
+        let priority = |&(x, y): &(u32, u32)| {
+            let min = Vec2::new(x as f32, y as f32) * size;
+            let max = min + Vec2::splat(size);
+
+            let in_view = min.x < view.max.x
+                && view.min.x < max.x
+                && min.y < view.max.y
+                && view.min.y < max.y;
+
+            (!in_view, ((min + max) / 2.0).distance_squared(center))
+        };
+
+        // stable, so tiles at the same distance stay row major
+        tiles.sort_by(|a, b| {
+            let (a_out, a_dist) = priority(a);
+            let (b_out, b_dist) = priority(b);
+            a_out.cmp(&b_out).then(a_dist.total_cmp(&b_dist))
+        });
+    }
+}
+
+/// A tile of the pyramid. Level 0 is the tilemap and every level above it splits each tile of
+/// the level below into four, so `index` at `level` is the child of `index / 2` at `level - 1`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub level: u8,
+    pub index: (u32, u32),
+}
+
+impl TileKey {
+    pub fn new(level: u8, index: (u32, u32)) -> Self {
+        Self { level, index }
+    }
+
+    /// The tile one level down that this tile is a quarter of
+    pub fn parent(&self) -> Self {
+        Self::new(self.level - 1, (self.index.0 / 2, self.index.1 / 2))
+    }
+}
+
+/// A tile above level 0 of the pyramid, binned from its parent when first needed. Tiles with
+/// anything to draw get their own texture, shown by a sprite in the main view.
+#[derive(Debug)]
+pub struct PyramidTile {
+    pub tile: Tile,
+    pub image: Option<Handle<Image>>,
+    pub sprite: Option<Entity>,
+    /// Waiting in the pyramid queue to be rendered
+    pub queued: bool,
+    /// Sent to the renderer, the texture is transparent until then
+    pub rendered: bool,
+    /// Pyramid update in which the tile was last in view, for eviction
+    pub last_used: u64,
+}
+
+/// Tiles above level 0 of the pyramid, those of them waiting to be rendered and those being
+/// rendered, which aren't evicted until they are done
+#[derive(Debug, Default)]
+pub struct TilePyramid {
+    pub tiles: HashMap<TileKey, PyramidTile>,
+    pub queue: VecDeque<TileKey>,
+    pub in_flight: HashSet<TileKey>,
+}
+
+impl TilePyramid {
+    /// Take the next tile to render off the queue
+    pub fn pop_queued(&mut self) -> Option<TileKey> {
+        let key = self.queue.pop_front()?;
+
+        if let Some(tile) = self.tiles.get_mut(&key) {
+            tile.queued = false;
+            tile.rendered = true;
+        }
+
+        self.in_flight.insert(key);
+
+        Some(key)
+    }
+
+    /// Mark the tile at `key` as rendered, letting it be evicted again
+    pub fn finish(&mut self, key: &TileKey) {
+        self.in_flight.remove(key);
+    }
+}
+
+/// What the main camera shows, re-rendered at the window's resolution once the camera settles.
+/// The region in view is tiled so that one texture pixel is one window pixel.
+#[derive(Debug, Default)]
+pub struct LiveView {
+    pub grid: TileGrid,
+    pub tilemap: Tilemap,
+    pub queue: VecDeque<(u32, u32)>,
+    /// Tiles of the current render that haven't finished, queued or in flight
+    pub remaining: usize,
+    /// A render is in progress, the sprite is hidden until it is done
+    pub rendering: bool,
+    pub image: Option<Handle<Image>>,
+    pub sprite: Option<Entity>,
+    /// Number of renders started, so that tiles still in flight from a cancelled render aren't
+    /// counted towards or drawn into the next one
+    pub render: u64,
+}
+
+impl LiveView {
+    /// Take the next tile to render off the queue
+    pub fn pop_queued(&mut self) -> Option<DrawTile> {
+        let index = self.queue.pop_front()?;
+
+        Some(DrawTile::LiveView {
+            render: self.render,
+            index,
+        })
+    }
+
+    /// Drop the tiles of a render in progress that haven't been sent to the renderer
+    pub fn cancel(&mut self) {
+        self.queue.clear();
+        self.remaining = 0;
+        self.rendering = false;
+    }
+}
 
 //
 // Resources
 //
 
+/// Resolution and styling of the tiled render. Lets the tile grid be traded off against
+/// texture memory per design without rebuilding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiledRendererSettings {
+    /// Number of tiles along each side of the tile grid
+    pub num_tiles: u32,
+    /// Side length of a tile in the accumulation texture, in pixels
+    pub tile_size_in_px: u32,
+    /// Alpha of shape fills
+    pub alpha: f32,
+    /// Width of shape outlines, in hi-res texture pixels
+    pub width: f32,
+    /// End style of every path
+    pub path_ends: PathEnds,
+    /// Height of label text, in database units
+    pub label_height: u32,
+    /// Labels smaller than this in the accumulation texture, in pixels, aren't drawn
+    pub min_label_px: f32,
+    /// Distance between the lines or dots of fill patterns in the accumulation texture, in
+    /// pixels
+    pub pattern_spacing_px: f32,
+    /// Highest level of the tile pyramid, each level doubling the resolution of the one below
+    pub max_zoom_level: u8,
+    /// Number of tiles above level 0 of the pyramid kept before those out of view are evicted
+    pub pyramid_cache_tiles: usize,
+    /// Re-render what the main camera shows at the window's resolution once it settles
+    pub live_view: bool,
+    /// How long the main camera has to stay still before the live view is re-rendered, in
+    /// seconds
+    pub live_view_settle_secs: f32,
+    /// Number of tiles rendered per frame, up to `MAX_BATCH_SIZE`. Each takes a hi-res texture
+    /// of its own, so batches are opt-in, and 1 renders one tile per GPU submission.
+    pub batch_size: usize,
+    /// Memory kept for tessellated shape outlines before the least recently used are evicted,
+    /// in bytes
+    pub tessellation_cache_bytes: usize,
+}
+
+impl Default for TiledRendererSettings {
+    fn default() -> Self {
+        Self {
+            num_tiles: 64,
+            tile_size_in_px: 64,
+            alpha: 0.1,
+            width: 10.0,
+            path_ends: PathEnds::Flush,
+            label_height: 1000,
+            min_label_px: 8.0,
+            pattern_spacing_px: 4.0,
+            max_zoom_level: 6,
+            pyramid_cache_tiles: 1024,
+            live_view: false,
+            live_view_settle_secs: 0.25,
+            batch_size: 1,
+            tessellation_cache_bytes: 256 << 20,
+        }
+    }
+}
+
+impl TiledRendererSettings {
+    /// Side length of the hi-res texture, and the maximum side length of the accumulation
+    /// texture, in pixels
+    pub fn texture_dim(&self) -> u32 {
+        self.num_tiles * self.tile_size_in_px
+    }
+
+    /// World space distance between the lines or dots of fill patterns when a tile is
+    /// `tile_size` database units across
+    pub fn pattern_spacing(&self, tile_size: f64) -> f64 {
+        self.pattern_spacing_px as f64 * tile_size / self.tile_size_in_px as f64
+    }
+
+    /// Height of labels in the accumulation texture, in pixels, when a tile is `tile_size`
+    /// database units across
+    pub fn label_px(&self, tile_size: u64) -> f32 {
+        self.label_height as f32 * self.tile_size_in_px as f32 / tile_size as f32
+    }
+}
+
+/// A GDSII layer, the layer number together with the datatype of the layer's purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LayerId {
+    pub layernum: i16,
+    pub datatype: i16,
+}
+
+impl LayerId {
+    pub fn new(layernum: i16, datatype: i16) -> Self {
+        Self { layernum, datatype }
+    }
+}
+
+impl fmt::Display for LayerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.layernum, self.datatype)
+    }
+}
+
+impl FromStr for LayerId {
+    type Err = String;
+
+    /// Parses `<layernum>/<datatype>`, e.g. `68/20`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s.split_once('/').and_then(|(layernum, datatype)| {
+            Some(LayerId::new(
+                layernum.trim().parse::<i16>().ok()?,
+                datatype.trim().parse::<i16>().ok()?,
+            ))
+        });
+
+        parsed.ok_or_else(|| format!("expected <layernum>/<datatype>, found {s:?}"))
+    }
+}
+
+/// Shapes are drawn at z values in `0.0..LAYER_Z_RANGE` by their layer's place in the stack,
+/// below labels and the hi-res camera
+pub const LAYER_Z_RANGE: f32 = 800.0;
+
+/// How the shapes on a layer are drawn
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerStyle {
+    /// Colour shapes are filled with, at the settings' `alpha`
+    pub fill: Color,
+    /// Colour shapes are outlined with
+    pub frame: Color,
+    /// Pattern the inside of shapes is filled with
+    pub fill_pattern: FillPattern,
+    /// Whether the layer is shown once the layout is loaded
+    pub visible: bool,
+    /// Depth the layer is drawn at, layers with a higher z are drawn over those below
+    pub z: f32,
+}
+
+impl LayerStyle {
+    /// A visible layer filled and outlined in `color`
+    pub fn from_color(color: Color) -> Self {
+        Self {
+            fill: color,
+            frame: color,
+            fill_pattern: FillPattern::Solid,
+            visible: true,
+            z: 0.0,
+        }
+    }
+}
+
+/// Style of every layer that shapes of the loaded layout are on
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
-pub struct Layers(HashMap<u8, Color>);
+pub struct Layers(HashMap<LayerId, LayerStyle>);
 
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
 pub struct LibLayers(pub raw::Layers);
 
+impl LibLayers {
+    /// The layer `el` is on. Purposes the library doesn't give a datatype get a negative one of
+    /// their own, which no GDSII datatype is, so they stay apart from datatype 0 and each other.
+    pub fn layer_id(&self, el: &raw::Element) -> LayerId {
+        let layer = self
+            .get(el.layer)
+            .expect("This Element's LayerKey does not exist in this Library's Layers");
+
+        let datatype = layer
+            .num(&el.purpose)
+            .unwrap_or_else(|| unnumbered_datatype(&el.purpose));
+
+        LayerId::new(layer.layernum, datatype)
+    }
+}
+
+/// Stand-in datatype for shapes whose purpose has no datatype in the library. Named purposes
+/// beyond the built in ones all share one.
+fn unnumbered_datatype(purpose: &raw::LayerPurpose) -> i16 {
+    match purpose {
+        raw::LayerPurpose::Drawing => -1,
+        raw::LayerPurpose::Pin => -2,
+        raw::LayerPurpose::Label => -3,
+        raw::LayerPurpose::Obstruction => -4,
+        raw::LayerPurpose::Outline => -5,
+        raw::LayerPurpose::Other(_) => -6,
+    }
+}
+
+/// Layers whose shapes are left out of the render
+#[derive(Debug, Default, Clone, Deref, DerefMut)]
+pub struct HiddenLayers(pub HashSet<LayerId>);
+
+/// Keys of the tiles holding at least one shape on each layer, so that toggling a layer only
+/// re-renders the tiles it touches
+#[derive(Debug, Default, Deref, DerefMut)]
+pub struct LayerTiles(pub HashMap<LayerId, Vec<(u32, u32)>>);
+
 pub struct RenderingDoneChannel {
     pub sender: Sender<()>,
     pub receiver: Receiver<()>,
 }
 
+/// Hi-res textures of the render slots, by slot
 #[derive(Deref)]
-pub struct HiResHandle(pub Handle<Image>);
+pub struct HiResHandles(pub Vec<Handle<Image>>);
 
 #[derive(Deref)]
 pub struct AccumulationHandle(pub Handle<Image>);
@@ -111,12 +663,67 @@ pub struct AccumulationHandle(pub Handle<Image>);
 // Events
 //
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct DrawTileEvent(pub (u32, u32));
+/// A tile to render
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DrawTile {
+    /// A tile of the pyramid, rendered into the accumulation texture at level 0 and into a
+    /// texture of its own above it
+    Pyramid(TileKey),
+    /// A tile of the live view, rendered into its place in the live view texture. `render` is
+    /// the live view render it belongs to, tiles of older renders are no longer drawn.
+    LiveView { render: u64, index: (u32, u32) },
+}
+
+/// One of the hi-res camera and accumulation camera pairs that render a tile each per frame
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RenderSlot(pub usize);
+
+impl RenderSlot {
+    /// Layer the shapes and labels of the slot's tile are drawn on, seen by its hi-res camera
+    pub fn shape_layer(&self) -> RenderLayers {
+        match self.0 {
+            0 => RenderLayers::default(),
+            n => RenderLayers::layer(1 + 2 * n as u8),
+        }
+    }
+
+    /// Layer of the slot's downscaling pass, seen by its accumulation camera
+    pub fn downscaling_layer(&self) -> RenderLayers {
+        match self.0 {
+            0 => DOWNSCALING_PASS_LAYER,
+            n => RenderLayers::layer(2 + 2 * n as u8),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DrawTileEvent {
+    pub tile: DrawTile,
+    /// Render slot the tile is drawn with, distinct for every tile drawn in the same frame
+    pub slot: RenderSlot,
+}
+
+/// Sent once the main camera has stopped moving for long enough to re-render what it shows
+#[derive(Debug, Default)]
+pub struct LiveViewSettledEvent;
 
 #[derive(Debug, Default)]
 pub struct RenderingCompleteEvent;
 
+/// Sent once every tile of the tilemap has been rendered into the accumulation texture, at the
+/// end of the first walk over it
+#[derive(Debug, Default)]
+pub struct TileWalkCompleteEvent;
+
+//
+// Labels
+//
+
+/// Label of the system sending the tiles to draw each frame. Systems binning, queueing or
+/// evicting tiles run before it, so that tiles don't change under a batch being drawn.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct SendTilesLabel;
+
 //
 // Components
 //
@@ -127,12 +734,32 @@ pub struct MainCamera;
 #[derive(Component, Clone, Debug, Default)]
 pub struct LyonShape;
 
+/// Text label drawn into the hi-res texture along with the shapes of a tile
+#[derive(Component, Clone, Debug, Default)]
+pub struct TileLabel;
+
 #[derive(Component, Debug)]
 pub struct HiResCam;
 
 #[derive(Component, Debug)]
 pub struct AccumulationCam;
 
+/// Sprite showing the accumulation texture in the main view
+#[derive(Component, Debug)]
+pub struct AccumulationSprite;
+
+/// Sprite outlining the accumulation texture in the main view
+#[derive(Component, Debug)]
+pub struct AccumulationOutline;
+
+/// Sprite showing the texture of a tile above level 0 of the pyramid in the main view
+#[derive(Component, Debug)]
+pub struct PyramidSprite;
+
+/// Sprite showing the live view texture in the main view
+#[derive(Component, Debug)]
+pub struct LiveViewSprite;
+
 #[derive(Bundle, Default)]
 pub struct LyonShapeBundle {
     #[bundle]
@@ -146,19 +773,63 @@ pub enum GeoShapeEnum {
     Polygon(GeoPolygon),
 }
 
+impl GeoShapeEnum {
+    /// Whether the shape touches the tile with `extents`
+    pub fn intersects(&self, extents: &GeoRect) -> bool {
+        match self {
+            GeoShapeEnum::Rect(r) => r.intersects(extents),
+            GeoShapeEnum::Polygon(p) => {
+                let extents = geo::Rect::new(
+                    (extents.min().x as f64, extents.min().y as f64),
+                    (extents.max().x as f64, extents.max().y as f64),
+                );
+
+                p.intersects(&extents)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Default, Deref, DerefMut)]
 pub struct FlattenedElems(pub Vec<raw::Element>);
 
+/// Text labels of the top cell and every cell below it, in top cell coordinates
+#[derive(Debug, Default, Deref, DerefMut)]
+pub struct FlattenedLabels(pub Vec<raw::TextElement>);
+
+/// Font labels are drawn with, labels aren't drawn without one
+#[derive(Debug, Default)]
+pub struct LabelFont(pub Option<Handle<Font>>);
+
+/// Everything derived from a library that is needed to render its tiles
+#[derive(Debug)]
+pub struct LoadedLayout {
+    pub layers: Layers,
+    /// Layers the layer properties file hides initially
+    pub hidden_layers: HiddenLayers,
+    pub lib_layers: LibLayers,
+    pub tilemap: Tilemap,
+    pub grid: TileGrid,
+    pub flattened_elems: FlattenedElems,
+    pub flattened_labels: FlattenedLabels,
+    pub world_to_render: WorldToRender,
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct OpenVlsirLibCompleteEvent;
 
+/// Sent when the library could not be opened or imported, or its top cell could not be loaded,
+/// carries the error chain outermost first
+#[derive(Debug, Default, Clone)]
+pub struct LibraryOpenFailedEvent(pub Vec<String>);
+
 #[derive(Debug, Default)]
 pub struct VlsirLib {
     pub lib: Option<Library>,
 }
 
 #[derive(Debug, Component, Deref, DerefMut)]
-pub struct LibraryWrapper(pub Task<Library>);
+pub struct LibraryWrapper(pub Task<Result<Library, LibraryOpenError>>);
 
 // LayerColor
 
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use bevy::prelude::*;
 use csv::Writer;
 
@@ -37,26 +39,20 @@ use crate::types::{Point, Rect, Tilemap};
 //     }
 // }
 
+/// Number of tiles along x and y of the tilemap, which need not be square
 pub fn get_grid_shape(grid: &Tilemap) -> (u32, u32) {
-    let (mut x_min, mut x_max, mut y_min, mut y_max) = (0, 0, 0, 0);
+    let (mut x_max, mut y_max) = (0, 0);
     for &(x, y) in grid.keys() {
-        if x < x_min {
-            x_min = x;
-        } else if x > x_max {
-            x_max = x;
-        }
-
-        if y < y_min {
-            y_min = y;
-        } else if y > y_max {
-            y_max = y;
-        }
+        x_max = x_max.max(x);
+        y_max = y_max.max(y);
     }
 
-    (x_max - x_min + 1, y_max - y_min + 1)
+    (x_max + 1, y_max + 1)
 }
 
-pub fn tilemap_stats_and_debug(grid: &Tilemap) {
+/// Log statistics of how shapes are spread over the tiles of `grid`, and write the shape count of
+/// each tile to `heatmap_csv`. `draw_labels` is whether tiles with only labels are rendered.
+pub fn tilemap_stats_and_debug(grid: &Tilemap, draw_labels: bool, heatmap_csv: Option<&Path>) {
     let mut counts: Vec<usize> = vec![];
 
     for v in grid.values() {
@@ -72,29 +68,47 @@ pub fn tilemap_stats_and_debug(grid: &Tilemap) {
 
     let grid_size = get_grid_shape(&grid);
 
-    let mut wtr = Writer::from_path("table_heatmap_data.csv").unwrap();
-
-    for iy in 0..grid_size.1 {
-        let mut row = vec![];
-        for ix in 0..grid_size.0 {
-            let count = grid.get(&(ix, iy)).unwrap().shapes.len();
-            row.push(count.to_string());
+    if let Some(path) = heatmap_csv {
+        match write_heatmap(grid, grid_size, path) {
+            Ok(()) => info!("wrote tile heatmap to {path:?}"),
+            Err(e) => error!("failed to write tile heatmap to {path:?}: {e}"),
         }
-
-        wtr.write_record(&row[..]).unwrap();
     }
 
-    wtr.flush().unwrap();
-
     let num_bins = (grid_size.0 * grid_size.1) as usize;
     let grid_occupancy = num_occupied_bins as f32 / num_bins as f32;
     info!(
         "grid_size: {grid_size:?}, num_bins: {num_bins}, num_occupied_bins: {num_occupied_bins}, num_rects_incl_duplicates: {num_rects_incl_duplicates}"
     );
     info!("grid_occupancy: {grid_occupancy}");
+    info!(
+        "empty bins skipped by the render walk: {}",
+        grid.values()
+            .filter(|tile| tile.is_empty(draw_labels))
+            .count()
+    );
     info!(
         "avg shapes per occupied bin: {}",
         num_rects_incl_duplicates as f32 / num_occupied_bins as f32
     );
     info!("min: {min}, max: {max}, avg_spob: {avg_spob}");
 }
+
+/// Write the number of shapes in each tile to a csv file at `path`, a row per row of tiles
+fn write_heatmap(grid: &Tilemap, grid_size: (u32, u32), path: &Path) -> Result<(), csv::Error> {
+    let mut wtr = Writer::from_path(path)?;
+
+    for iy in 0..grid_size.1 {
+        let mut row = vec![];
+        for ix in 0..grid_size.0 {
+            let count = grid.get(&(ix, iy)).unwrap().shapes.len();
+            row.push(count.to_string());
+        }
+
+        wtr.write_record(&row[..])?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}